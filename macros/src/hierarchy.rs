@@ -0,0 +1,169 @@
+use crate::item::LockItem;
+use crate::quote::{ident, quote, Error};
+use crate::Macro;
+use proc_macro::{Delimiter, Group, Ident, Span, TokenStream, TokenTree};
+
+/// The `macro_rules!` macro a `lock_hierarchy!` defines, which the macros taking locks defer to.
+const HIERARCHY: &str = "__lock_order_hierarchy";
+
+/// Parse `lock_hierarchy!(a < b < c)` and define the macro carrying its ranks to the locks.
+///
+/// Each lock must be a name, given once:
+///
+/// ```compile_fail
+/// # use lock_order::lock_hierarchy;
+/// lock_hierarchy!(a < b < a);
+/// ```
+///
+/// ```compile_fail
+/// # use lock_order::lock_hierarchy;
+/// lock_hierarchy!(a < self.b);
+/// ```
+pub(crate) fn declare(item: TokenStream) -> Result<TokenStream, Error> {
+    let ranks = parse_ranks(item)?;
+    for (i, name) in ranks.iter().enumerate() {
+        if let Some(earlier) = ranks[..i]
+            .iter()
+            .find(|r| r.to_string() == name.to_string())
+        {
+            return Err(Error::new(
+                name.span(),
+                format!("`{}` is in the lock hierarchy more than once", name),
+            )
+            .note(
+                earlier.span(),
+                format!("`{}` is first ranked here", earlier),
+            ));
+        }
+    }
+
+    let declaration = format!(
+        "#[allow(unused_macros)]
+        macro_rules! {} {{
+            ($$($$tokens:tt)*) => {{ ::lock_order::__lock_ranked!({{ $0 }} $$($$tokens)*) }};
+        }}",
+        HIERARCHY
+    );
+    Ok(quote(&declaration, &[write_ranks(&ranks)]))
+}
+
+/// The call handing `item`, given to `mac`, over to the `lock_hierarchy!` in scope.
+pub(crate) fn defer(mac: Macro, item: TokenStream) -> TokenStream {
+    quote(
+        &format!("{}!({} $0)", HIERARCHY, mac.name()),
+        &[braced(item)],
+    )
+}
+
+/// Split the input to `__lock_ranked!` back into the macro the locks were given to, the ranks
+/// from the hierarchy and the original input.
+pub(crate) fn resume(item: TokenStream) -> Result<(Macro, Vec<Ident>, TokenStream), Error> {
+    let mut tokens = item.into_iter();
+    match (tokens.next(), tokens.next(), tokens.next(), tokens.next()) {
+        (
+            Some(TokenTree::Group(ranks)),
+            Some(TokenTree::Ident(name)),
+            Some(TokenTree::Group(input)),
+            None,
+        ) if ranks.delimiter() == Delimiter::Brace && input.delimiter() == Delimiter::Brace => {
+            match Macro::from_name(&name.to_string()) {
+                Some(mac) => Ok((mac, parse_ranks(ranks.stream())?, input.stream())),
+                None => Err(misused()),
+            }
+        }
+        _ => Err(misused()),
+    }
+}
+
+/// Give each of `locks` its place in `ranks`, by the name its guard is bound to.
+///
+/// Every lock must be in the hierarchy:
+///
+/// ```compile_fail
+/// # use lock_order::{lock, lock_hierarchy};
+/// lock_hierarchy!(a < b);
+/// # let a = std::sync::Mutex::new(1);
+/// # let c = std::sync::Mutex::new(1);
+/// lock!(order = hierarchy; a, c);
+/// ```
+pub(crate) fn rank(locks: &mut [LockItem], ranks: &[Ident]) -> Result<(), Error> {
+    for lock in locks.iter_mut().filter(|lock| lock.pinned.is_none()) {
+        let name = lock.binding_name();
+        match ranks.iter().position(|r| r.to_string() == name) {
+            Some(rank) => lock.rank = Some(rank as u64),
+            None => {
+                let mut error = Error::new(
+                    lock.binding_span(),
+                    format!("`{}` has no rank in the lock hierarchy", name),
+                );
+                if let Some(first) = ranks.first() {
+                    error = error.note(first.span(), "the lock hierarchy is declared here");
+                }
+                return Err(error);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Parse `a < b < c` into the names in it, from first to last.
+fn parse_ranks(item: TokenStream) -> Result<Vec<Ident>, Error> {
+    let mut ranks = Vec::new();
+    let mut tokens = item.into_iter();
+    let mut separator = None;
+    loop {
+        match tokens.next() {
+            Some(TokenTree::Ident(name)) => ranks.push(name),
+            Some(other) => {
+                return Err(Error::new(
+                    other.span(),
+                    "expected the name of a lock in the hierarchy",
+                ))
+            }
+            None if ranks.is_empty() => {
+                return Err(Error::new(
+                    Span::call_site(),
+                    "expected at least one lock in the hierarchy, as in `a < b < c`",
+                ))
+            }
+            None => {
+                return Err(Error::new(
+                    separator.unwrap_or_else(Span::call_site),
+                    "unexpected trailing `<`",
+                ))
+            }
+        }
+        match tokens.next() {
+            None => return Ok(ranks),
+            Some(TokenTree::Punct(p)) if p.as_char() == '<' => separator = Some(p.span()),
+            Some(other) => {
+                return Err(Error::new(
+                    other.span(),
+                    "expected `<` between the locks in the hierarchy",
+                ))
+            }
+        }
+    }
+}
+
+fn write_ranks(ranks: &[Ident]) -> TokenStream {
+    let mut out = TokenStream::new();
+    for (i, rank) in ranks.iter().enumerate() {
+        if i > 0 {
+            out.extend(quote("<", &[]));
+        }
+        out.extend(ident(rank));
+    }
+    out
+}
+
+fn braced(tokens: TokenStream) -> TokenStream {
+    TokenTree::Group(Group::new(Delimiter::Brace, tokens)).into()
+}
+
+fn misused() -> Error {
+    Error::new(
+        Span::call_site(),
+        "`__lock_ranked!` is only for use by `lock_hierarchy!`",
+    )
+}
@@ -0,0 +1,384 @@
+use crate::options::{Backend, Options, Poison};
+use crate::quote::{ident, parenthesised, quote, string};
+use proc_macro::{Delimiter, Ident, Literal, Span, TokenStream, TokenTree};
+
+/// How a single lock is acquired.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub(crate) enum Mode {
+    /// `Mutex::lock`
+    #[default]
+    Lock,
+    /// `RwLock::read`
+    Read,
+    /// `RwLock::write`
+    Write,
+}
+
+impl Mode {
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "read" => Some(Mode::Read),
+            "write" => Some(Mode::Write),
+            _ => None,
+        }
+    }
+
+    fn method(self) -> &'static str {
+        match self {
+            Mode::Lock => "lock",
+            Mode::Read => "read",
+            Mode::Write => "write",
+        }
+    }
+
+    /// The method called on the lock to take it, `lock` or `try_lock` for instance, given the
+    /// span of the lock so that a lock without the method is reported there.
+    fn call(self, prefix: &str, suffix: &str, span: Span) -> TokenStream {
+        let method = format!("{}{}{}", prefix, self.method(), suffix);
+        ident(&Ident::new(&method, span))
+    }
+}
+
+/// The variable bound alongside the guard bound to `binding`, holding what's released along
+/// with the guard, which with the `checked` feature is the lock's record as held. `unlock!` needs
+/// to find it to release it, and to be sure it's given a guard.
+pub(crate) fn marker(binding: &Ident) -> Ident {
+    let name = binding.to_string();
+    // A raw identifier such as `r#type` is the name `type`, which needs no escaping after a prefix.
+    let name = name.strip_prefix("r#").unwrap_or(&name);
+    Ident::new(&format!("__lock_order_locked_{}", name), binding.span())
+}
+
+#[derive(Clone, Debug, Default)]
+pub(crate) struct LockItem {
+    /// The tokens of the expression evaluating to the lock.
+    pub(crate) expression: Vec<TokenTree>,
+    /// The name given with `as`, to bind instead of the last identifier.
+    pub(crate) alias: Option<Ident>,
+    /// The expression written out compactly, as in `self.locks[0]`, to name the lock by.
+    pub(crate) full_identifier: String,
+    /// The `mut` keyword, if the guard is bound mutably.
+    pub(crate) mutable: Option<Ident>,
+    pub(crate) mode: Mode,
+    /// Where the lock comes in the hierarchy, for the orders that use one.
+    pub(crate) rank: Option<u64>,
+    /// Where the rank was given inline with `#[rank = N]`, if it was, which takes the place of any
+    /// rank the order would give the lock.
+    pub(crate) pinned: Option<Span>,
+    /// The expression for the rank of the lock, for the orders which only know it once the locks
+    /// are taken.
+    pub(crate) rank_of: Option<TokenStream>,
+    /// The variable holding a reference to the lock, for the orders which need the lock for its
+    /// rank or address before taking it, so that the expression is only evaluated once.
+    pub(crate) local: Option<Ident>,
+}
+
+impl LockItem {
+    pub(crate) fn add(&mut self, token: TokenTree) {
+        // Only neighbouring words need spacing out, as in `*self.a` or `self.locks[0]`.
+        let word = |t: &TokenTree| matches!(t, TokenTree::Ident(_) | TokenTree::Literal(_));
+        if self.expression.last().is_some_and(word) && word(&token) {
+            self.full_identifier.push(' ');
+        }
+        self.full_identifier += &token.to_string();
+        self.expression.push(token);
+    }
+
+    /// Where the lock was written, for reporting problems with it.
+    pub(crate) fn span(&self) -> Span {
+        self.expression
+            .last()
+            .map_or_else(Span::call_site, TokenTree::span)
+    }
+
+    /// Where the name the guard is bound to was written.
+    pub(crate) fn binding_span(&self) -> Span {
+        self.binding().map_or_else(|| self.span(), Ident::span)
+    }
+
+    /// The identifier the expression ends in, if it ends in one that can name a variable.
+    pub(crate) fn last_identifier(&self) -> Option<&Ident> {
+        match self.expression.last() {
+            Some(TokenTree::Ident(id))
+                if !matches!(id.to_string().as_str(), "self" | "Self" | "crate" | "super") =>
+            {
+                Some(id)
+            }
+            _ => None,
+        }
+    }
+
+    /// The variable the guard is bound to, if there's a name for it.
+    pub(crate) fn binding(&self) -> Option<&Ident> {
+        self.alias.as_ref().or_else(|| self.last_identifier())
+    }
+
+    /// The name of the bound variable, or the whole lock if it doesn't have one.
+    pub(crate) fn binding_name(&self) -> String {
+        self.binding()
+            .map_or_else(|| self.full_identifier.clone(), Ident::to_string)
+    }
+
+    /// The last identifier of the lock itself, or the whole lock if it doesn't end in one.
+    pub(crate) fn field(&self) -> String {
+        self.last_identifier()
+            .map_or_else(|| self.full_identifier.clone(), Ident::to_string)
+    }
+
+    /// The expression the lock is a field of and the field itself, if it's written as a field of a
+    /// path, ie `self.locks` and `connections` for `self.locks.connections`.
+    pub(crate) fn field_access(&self) -> Option<(&[TokenTree], &TokenTree)> {
+        match self.expression.as_slice() {
+            [parent @ .., TokenTree::Punct(dot), field]
+                if !parent.is_empty()
+                    && dot.as_char() == '.'
+                    && matches!(field, TokenTree::Ident(_) | TokenTree::Literal(_))
+                    && !parent.iter().any(|t| {
+                        matches!(t, TokenTree::Punct(p) if p.as_char() != '.' && p.as_char() != ':')
+                    }) =>
+            {
+                Some((parent, field))
+            }
+            _ => None,
+        }
+    }
+
+    /// The lock as a string literal, for errors naming it.
+    pub(crate) fn name(&self) -> TokenStream {
+        string(&self.full_identifier)
+    }
+
+    /// The pattern binding the guard and its marker, ie `(__lock_order_locked_connections, mut
+    /// connections)`, for a value from one of the `acquire` methods.
+    ///
+    /// The marker comes first so that it's dropped after the guard.
+    pub(crate) fn pattern(&self) -> TokenStream {
+        let binding = self.binding().expect("locks are bound");
+        let mut guard = TokenStream::new();
+        if let Some(mutable) = &self.mutable {
+            guard.extend(ident(mutable));
+        }
+        guard.extend(ident(binding));
+        quote("($0, $1)", &[ident(&marker(binding)), guard])
+    }
+
+    /// The expression for the guard bound by [`LockItem::pattern`] and its marker, to be bound by
+    /// the pattern again.
+    pub(crate) fn bound(&self) -> TokenStream {
+        let binding = self.binding().expect("locks are bound");
+        quote("($0, $1)", &[ident(&marker(binding)), ident(binding)])
+    }
+
+    /// The expression for the lock, ready to have methods called on it.
+    pub(crate) fn receiver(&self) -> TokenStream {
+        if let Some(local) = &self.local {
+            return quote("(*$0)", &[ident(local)]);
+        }
+        let expression: TokenStream = self.expression.iter().cloned().collect();
+        // Only a path of fields, method calls and indexing can be used as is, anything with an
+        // operator such as `*lock` needs parentheses to take the lock rather than its result.
+        let operator = self
+            .expression
+            .iter()
+            .any(|t| matches!(t, TokenTree::Punct(p) if p.as_char() != '.' && p.as_char() != ':'));
+        if operator {
+            parenthesised(expression)
+        } else {
+            expression
+        }
+    }
+
+    /// The expression `acquire` gives for the lock, paired with its marker as the two are bound
+    /// by [`LockItem::pattern`]. With the `checked` feature the marker records the lock as held by
+    /// the thread, and is otherwise `()`.
+    fn tracked(
+        &self,
+        backend: Backend,
+        acquire: impl FnOnce(TokenStream) -> TokenStream,
+    ) -> TokenStream {
+        // Tasks move between threads while holding tokio locks, so only a thread's own locks are
+        // tracked.
+        if !cfg!(feature = "checked") || backend == Backend::Tokio {
+            return quote("((), $0)", &[acquire(self.receiver())]);
+        }
+        // A call is evaluated once and derefed to the lock it gives, while anything else, a lock
+        // already held in a variable included, is a place holding the lock or a pointer to it.
+        let call = self.local.is_none()
+            && self.expression.len() > 1
+            && matches!(
+                self.expression.last(),
+                Some(TokenTree::Group(g)) if g.delimiter() == Delimiter::Parenthesis
+            );
+        let (lock, address) = if call {
+            (self.receiver(), quote("&*__lock_order_lock", &[]))
+        } else {
+            (
+                quote("&$0", &[self.receiver()]),
+                quote("__lock_order_lock", &[]),
+            )
+        };
+        let rank = match (self.rank, &self.rank_of) {
+            (Some(rank), _) => quote(
+                "Some($0)",
+                &[TokenTree::Literal(Literal::u64_suffixed(rank)).into()],
+            ),
+            (None, Some(rank_of)) => quote(
+                "Some(::core::primitive::u64::from($0))",
+                std::slice::from_ref(rank_of),
+            ),
+            (None, None) => quote("None", &[]),
+        };
+        // Call sites ranking locks of a class inline are checked to agree on its rank.
+        let pin = match (self.pinned, self.rank) {
+            (Some(_), Some(pinned)) => quote(
+                "::lock_order::__checked::pin(__lock_order_class, $0, $1);",
+                &[
+                    self.name(),
+                    TokenTree::Literal(Literal::u64_suffixed(pinned)).into(),
+                ],
+            ),
+            _ => TokenStream::new(),
+        };
+        // The parent of a field is only there for its type, so is never evaluated, and a variable
+        // is scoped to the function it's in, as named by a function declared there.
+        let (parent, field) = match self.field_access() {
+            Some((parent, field)) => (
+                quote(
+                    "{
+                        let mut __lock_order_parent = ::core::marker::PhantomData;
+                        if false {
+                            __lock_order_parent = ::lock_order::__checked::parent(&$0);
+                        }
+                        __lock_order_parent
+                    }",
+                    &[parent.iter().cloned().collect()],
+                ),
+                field.to_string(),
+            ),
+            None => (
+                quote(
+                    "{
+                        fn __lock_order_scope() {}
+                        ::lock_order::__checked::parent(&__lock_order_scope)
+                    }",
+                    &[],
+                ),
+                self.field(),
+            ),
+        };
+        let acquisition = acquire(quote("__lock_order_lock", &[]));
+        // The lock is ordered by its class, the field or variable it's taken through, and the entry also records what the guard derefs to, so that `assert_holds!` can be
+        // given the guard in place of the lock.
+        quote(
+            "{
+                let __lock_order_lock = $0;
+                let __lock_order_address = {
+                    use ::lock_order::__checked::{ByGuard as _, ByLock as _, ByPlace as _};
+                    (&&&::lock_order::__checked::Probe($1)).__address()
+                };
+                let __lock_order_class = ::lock_order::__checked::class($1, $6, $7);
+                $5
+                let __lock_order_entry = ::lock_order::__checked::enter(
+                    __lock_order_address,
+                    __lock_order_class,
+                    $2,
+                    $3,
+                );
+                let __lock_order_guard = $4;
+                let __lock_order_target = {
+                    use ::lock_order::__checked::{ByGuard as _, ByPlace as _};
+                    (&&&::lock_order::__checked::Probe(&__lock_order_guard)).__address()
+                };
+                (__lock_order_entry.guarding(__lock_order_target), __lock_order_guard)
+            }",
+            &[
+                lock,
+                address,
+                self.name(),
+                rank,
+                acquisition,
+                pin,
+                parent,
+                string(&field),
+            ],
+        )
+    }
+
+    /// The expression taking this lock and evaluating to its guard and marker.
+    pub(crate) fn acquire(&self, backend: Backend, poison: Poison) -> TokenStream {
+        self.tracked(backend, |lock| {
+            let call = quote("$0.$1()", &[lock, self.mode.call("", "", self.span())]);
+            match backend {
+                Backend::Std => poison.unwrap(call, self),
+                Backend::ParkingLot => call,
+                Backend::Tokio => quote("$0.await", &[call]),
+            }
+        })
+    }
+
+    /// The expression taking this lock as `options` say, evaluating to its guard and marker.
+    pub(crate) fn acquire_with(&self, backend: Backend, options: &Options) -> TokenStream {
+        match options.timeout {
+            Some(_) => self.acquire_before_deadline(backend),
+            None => self.acquire(backend, options.poison),
+        }
+    }
+
+    /// The expression taking this `OrderedMutex` or `OrderedRwLock` with the `Level` token
+    /// `__lock_order_held`, evaluating to its guard and marker.
+    pub(crate) fn acquire_at_level(&self, poison: Poison) -> TokenStream {
+        self.tracked(Backend::Std, |lock| {
+            let call = quote(
+                "$0.$1(__lock_order_held)",
+                &[lock, self.mode.call("__", "_at", self.span())],
+            );
+            poison.unwrap(call, self)
+        })
+    }
+
+    /// The expression taking this lock before `__lock_order_deadline`, evaluating to its guard and
+    /// marker or returning a `TimeoutError` with `?`.
+    pub(crate) fn acquire_before_deadline(&self, backend: Backend) -> TokenStream {
+        let timed_out = quote("Err(::lock_order::TimeoutError::new($0))?", &[self.name()]);
+        self.tracked(backend, |lock| match backend {
+            Backend::Std => unreachable!("std locks are rejected with a timeout"),
+            Backend::ParkingLot => quote(
+                "match $0.$1(__lock_order_deadline) { Some(guard) => guard, None => $2 }",
+                &[
+                    lock,
+                    self.mode.call("try_", "_until", self.span()),
+                    timed_out,
+                ],
+            ),
+            Backend::Tokio => quote(
+                "match ::tokio::time::timeout_at(
+                    ::tokio::time::Instant::from_std(__lock_order_deadline), $0.$1()).await
+                { Ok(guard) => guard, Err(_) => $2 }",
+                &[lock, self.mode.call("", "", self.span()), timed_out],
+            ),
+        })
+    }
+
+    /// The expression trying to take this lock and evaluating to an `Option` of its guard.
+    ///
+    /// The guard is handed back rather than bound with a marker, so the lock isn't recorded as
+    /// held with the `checked` feature.
+    pub(crate) fn try_acquire(&self, backend: Backend, poison: Poison) -> TokenStream {
+        let attempt = quote(
+            "$0.$1()",
+            &[self.receiver(), self.mode.call("try_", "", self.span())],
+        );
+        match backend {
+            Backend::Std => quote(
+                "match $0 {
+                    Ok(guard) => Some(guard),
+                    Err(::std::sync::TryLockError::WouldBlock) => None,
+                    Err(::std::sync::TryLockError::Poisoned(e)) => $1,
+                }",
+                &[attempt, poison.try_unwrap(self)],
+            ),
+            Backend::ParkingLot => attempt,
+            Backend::Tokio => quote("$0.ok()", &[attempt]),
+        }
+    }
+}
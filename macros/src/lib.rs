@@ -0,0 +1,586 @@
+//! Procedural macros for the [`lock_order`](https://docs.rs/lock_order) crate, which re-exports
+//! them alongside the types their expansions use.
+
+mod hierarchy;
+mod item;
+mod manifest;
+mod options;
+mod parse;
+mod quote;
+mod ranks;
+mod wait;
+
+use item::LockItem;
+use options::{Backend, Order};
+use parse::{parse, Invocation, Kind};
+use proc_macro::TokenStream;
+use quote::{ident, parenthesised, quote, separated, Error};
+
+/// Lock one or more locks at a time.
+///
+/// This takes multiple lock arguments (with an optional `mut` flag) and creates a single let
+/// expression binding the `.lock().unwrap()` into variables of the same name as the last identifier
+/// in the lock expression. Prefixing a lock with `read` or `write` uses `.read().unwrap()` or
+/// `.write().unwrap()` instead, for `RwLock`.
+///
+/// The locks can be preceded by `parking_lot:` to use the bare `.lock()`, `.read()` and
+/// `.write()` of non-poisoning locks such as `parking_lot`, ie `lock!(parking_lot: a, mut b)`.
+/// `std:` is the default and can also be given explicitly. `tokio:` is covered by [`async_lock!`].
+///
+/// Any expression evaluating to a lock can be used, such as `self.shard(3)`, `self.locks[i]` or
+/// `*lock`, but the name to bind it to must then be given with `as` if the expression doesn't end
+/// in one.
+///
+/// A lock can be bound to a different name with `as`, such as when two locks share the same last
+/// identifier or it isn't a valid name, ie `lock!(mut self.a.inner as a_inner, self.0 as zeroth)`.
+/// The locks are sorted by the bound name, including any `as` alias, unless `order = field;` is
+/// given before the locks to sort by the last identifier of each lock itself, or
+/// `order = hierarchy;` to sort by the ranks given to the bound names by [`lock_hierarchy!`]. If
+/// the crate has a `lock_order.toml` the locks are sorted by their ranks in it instead, see the
+/// crate documentation. `order = rank;` takes fields in the order of their `#[lock_rank(N)]`
+/// attributes, see [`LockRanks`].
+///
+/// `order = path;` sorts by the whole of each lock as written, `order = natural;` by the bound
+/// name with runs of digits compared as numbers, and `order = address;` takes std locks lowest
+/// address first, comparing them when they're taken. Locks which tie, such as `self.a.state` and
+/// `self.b.state` with `order = field;`, are sorted by the whole lock as written, so `self.a.state`
+/// is taken first whichever is written first.
+///
+/// A lock can be given a rank inline with `#[rank = N]` before it, taking the place of the rank
+/// the order gives it, as in `lock!(#[rank = 1] self.registry, #[rank = 5] mut self.queue)`.
+/// Ordering by names, every lock must then have one, while `order = hierarchy;`, a
+/// `lock_order.toml` and `order = rank;` only need one for the locks whose rank it changes.
+///
+/// `OrderedMutex` and `OrderedRwLock` locks can be given a `level = token;` option naming a
+/// variable holding a `Level` token, in which case they're taken in the order of their levels,
+/// each checked at compile time to be above the token's. `token` is then shadowed by a new token
+/// for the locks taken.
+///
+/// For `parking_lot:` and `tokio:` locks a `timeout = <Duration>;` can be given before the locks,
+/// ie `lock!(parking_lot: timeout = Duration::from_millis(50); a, b)`. The whole set of locks must
+/// then be taken within that time, otherwise the locks already taken are released and a
+/// `lock_order::TimeoutError` naming the lock that timed out is returned with `?`, so the enclosing
+/// function must return a `Result` whose error type implements `From<TimeoutError>`.
+///
+/// For `std` locks, what happens when a lock has been poisoned by a thread panicking while holding
+/// it can be chosen with a `poison = ...;` option before the locks:
+///
+/// - `poison = panic;` is the default, and unwraps the lock result.
+/// - `poison = recover;` takes the guard anyway with `PoisonError::into_inner`.
+/// - `poison = propagate;` releases the locks already taken and returns a
+///   `lock_order::PoisonError` naming the poisoned lock with `?`, so the enclosing function must
+///   return a `Result` whose error type implements `From<PoisonError>`.
+///
+/// This means that if something is passed such as:
+///
+/// ```
+/// # use lock_order::lock;
+/// # use std::sync::Mutex;
+/// # struct Inner {
+/// #    connections: Mutex<u32>,
+/// # }
+/// # struct Test {
+/// #    locks: Inner,
+/// # }
+/// # impl Test {
+/// # fn test(&self) {
+/// lock!(mut self.locks.connections);
+/// # }
+/// # }
+/// ```
+///
+/// Then the output will be something similar to:
+///
+/// ```
+/// # use lock_order::lock;
+/// # use std::sync::Mutex;
+/// # struct Inner {
+/// #    connections: Mutex<u32>,
+/// # }
+/// # struct Test {
+/// #     locks: Inner,
+/// # }
+/// # impl Test {
+/// # fn test(&self) {
+/// let (mut connection) = (self.locks.connections.lock().unwrap());
+/// # }
+/// # }
+/// ```
+#[proc_macro]
+pub fn lock(item: TokenStream) -> TokenStream {
+    expand(item, Macro::Lock)
+}
+
+/// Lock one or more async locks at a time.
+///
+/// This is the same as [`lock!`] but for `tokio::sync::Mutex` and `tokio::sync::RwLock`, and so
+/// must be used in an `async` context. Each lock is awaited in turn, in the same order [`lock!`]
+/// would take them, so that:
+///
+/// ```ignore
+/// async_lock!(mut queue, read config);
+/// ```
+///
+/// Will expand to:
+///
+/// ```ignore
+/// let (config, mut queue) = (config.read().await, queue.lock().await);
+/// ```
+///
+/// This is equivalent to `lock!(tokio: mut queue, read config)`.
+#[proc_macro]
+pub fn async_lock(item: TokenStream) -> TokenStream {
+    expand(item, Macro::AsyncLock)
+}
+
+/// Lock one or more locks at a time if they are all free, without blocking.
+///
+/// This takes the same arguments as [`lock!`] and tries each lock in the same order with
+/// `.try_lock()`, `.try_read()` or `.try_write()`. If every lock is taken the result is `Some` of
+/// the guards, in the order the locks were written, otherwise any locks already taken are released
+/// again and the result is `None`. As nothing is bound by the macro itself, `mut` has no effect
+/// and the mutability comes from the pattern the guards are bound with:
+///
+/// ```
+/// # use lock_order::try_lock;
+/// # use std::sync::Mutex;
+/// let queue = Mutex::new(1);
+/// let config = Mutex::new(2);
+/// if let Some((mut queue, config)) = try_lock!(queue, config) {
+///     *queue += *config;
+/// }
+/// assert_eq!(*queue.lock().unwrap(), 3);
+/// ```
+///
+/// A lock poisoned by a panicking thread is handled the same as by [`lock!`], so panics unless a
+/// `poison` policy is given.
+#[proc_macro]
+pub fn try_lock(item: TokenStream) -> TokenStream {
+    expand(item, Macro::TryLock)
+}
+
+/// Lock one or more locks for the length of a body, which the macro evaluates to.
+///
+/// This takes the same arguments as [`lock!`], followed by `=>` and the body to run while holding
+/// the locks. The guards are bound for the body alone and dropped as soon as it's finished, rather
+/// than at the end of the enclosing block:
+///
+/// ```
+/// # use lock_order::with_locks;
+/// # use std::sync::Mutex;
+/// let queue = Mutex::new(1);
+/// let config = Mutex::new(2);
+/// let total = with_locks!(mut queue, config => {
+///     *queue += *config;
+///     *queue
+/// });
+/// assert_eq!(total, 3);
+/// // The locks are already released.
+/// assert_eq!(*queue.lock().unwrap(), 3);
+/// ```
+///
+/// The body can also be a single expression, as in `with_locks!(queue => queue.len())`.
+#[proc_macro]
+pub fn with_locks(item: TokenStream) -> TokenStream {
+    match parse::split_body(item) {
+        Ok((locks, body)) => quote(
+            "{
+                ::lock_order::lock!($0);
+                let __lock_order_value = $1;
+                __lock_order_value
+            }",
+            &[locks, body],
+        ),
+        Err(error) => error.to_compile_error(),
+    }
+}
+
+/// Lock one or more further locks while already holding others, which they must come after.
+///
+/// This takes the same arguments as [`lock!`], for adding to the locks already taken by another
+/// macro once it turns out more are needed, rather than releasing them all to take them again in
+/// order. The locks must be ranked to be compared with those held, with `order = hierarchy;`,
+/// `order = rank;`, a `lock_order.toml` or a `level = token;`:
+///
+/// ```
+/// use lock_order::{lock, lock_hierarchy, lock_more};
+/// use std::sync::Mutex;
+///
+/// lock_hierarchy!(registry < state < queue);
+///
+/// let registry = Mutex::new(1);
+/// let queue = Mutex::new(2);
+/// lock!(order = hierarchy; mut registry);
+/// if *registry > 0 {
+///     lock_more!(order = hierarchy; mut queue);
+///     *queue += *registry;
+/// }
+/// ```
+///
+/// With a `level` token this is checked at compile time, the same as by [`lock!`]. Otherwise it's
+/// checked when the locks are taken with the `checked` feature of `lock_order`, panicking if one
+/// of them doesn't rank after every ranked lock the thread already holds.
+///
+/// ```compile_fail
+/// # use lock_order::lock_more;
+/// # use std::sync::Mutex;
+/// let queue = Mutex::new(1);
+/// // Names alone don't say where `queue` comes among the locks held.
+/// lock_more!(queue);
+/// ```
+#[proc_macro]
+pub fn lock_more(item: TokenStream) -> TokenStream {
+    expand(item, Macro::LockMore)
+}
+
+/// Release one or more locks taken by [`lock!`] early, before the end of the block.
+///
+/// This takes the names the guards were bound to, and drops them in the order given:
+///
+/// ```
+/// # use lock_order::{lock, unlock};
+/// # use std::sync::Mutex;
+/// let locks = (Mutex::new(1), Mutex::new(2));
+/// lock!(mut locks.0 as queue, locks.1 as config);
+/// *queue += *config;
+/// unlock!(config);
+/// // `config` is free again while `queue` is still held.
+/// assert!(locks.1.try_lock().is_ok());
+/// ```
+///
+/// It's a compile error for a name not to be a guard bound by [`lock!`], or one of the macros
+/// built on it, in scope:
+///
+/// ```compile_fail
+/// # use lock_order::unlock;
+/// # use std::sync::Mutex;
+/// let queue = Mutex::new(1);
+/// let guard = queue.lock().unwrap();
+/// unlock!(guard);
+/// ```
+#[proc_macro]
+pub fn unlock(item: TokenStream) -> TokenStream {
+    let guards = match parse::parse_guards(item) {
+        Ok(guards) => guards,
+        Err(error) => return error.to_compile_error(),
+    };
+    let mut releases = TokenStream::new();
+    for guard in &guards {
+        releases.extend(quote(
+            "::lock_order::__Unlock::__unlock($0, $1);",
+            &[ident(&item::marker(guard)), ident(guard)],
+        ));
+    }
+    quote("{ $0 }", &[releases])
+}
+
+/// Wait on a `Condvar` while holding locks taken by [`lock!`], and take them all again in order.
+///
+/// This takes the condvar and the lock it's used with, then after a `;` any other locks held,
+/// given the same way as to [`lock!`] with the same options. The other guards are released in
+/// reverse of the order they were taken in, and the wait releases the lock it's used with. Once
+/// woken, every lock is taken again in order and bound to the same names, so a lock is never
+/// waited on while holding one that comes after it.
+///
+/// ```
+/// use lock_order::{lock, wait_locked};
+/// use std::sync::{Arc, Condvar, Mutex};
+/// use std::thread;
+///
+/// struct Shared {
+///     queue: Mutex<Vec<u32>>,
+///     config: Mutex<u32>,
+///     ready: Condvar,
+/// }
+///
+/// let shared = Arc::new(Shared {
+///     queue: Mutex::new(Vec::new()),
+///     config: Mutex::new(2),
+///     ready: Condvar::new(),
+/// });
+/// let producer = Arc::clone(&shared);
+/// thread::spawn(move || {
+///     lock!(mut producer.queue);
+///     queue.push(1);
+///     producer.ready.notify_one();
+/// });
+///
+/// lock!(mut shared.queue, shared.config);
+/// // Waits while `queue` is empty, without holding `config`.
+/// wait_locked!(shared.ready, mut shared.queue; shared.config; while queue.is_empty());
+/// assert_eq!(queue[0] * *config, 2);
+/// ```
+///
+/// Without a `while` condition this waits once, and so can wake spuriously. With a
+/// `timeout = <Duration>;` option it returns a `TimeoutError` naming the lock from the enclosing
+/// function once the time is up, as [`lock!`] does. Only std locks can be waited with, a `level`
+/// token can't be given back, and `order = rank;` and `order = address;` don't bind the guards
+/// themselves, so it's a compile error to use any of them.
+///
+/// ```compile_fail
+/// # use lock_order::wait_locked;
+/// # use std::sync::{Condvar, Mutex};
+/// let queue = Mutex::new(1);
+/// let ready = Condvar::new();
+/// // The condvar is missing its lock.
+/// wait_locked!(ready);
+/// ```
+#[proc_macro]
+pub fn wait_locked(item: TokenStream) -> TokenStream {
+    expand(item, Macro::WaitLocked)
+}
+
+/// Declare the order locks must be taken in, from first to last.
+///
+/// This takes the names of the locks separated by `<`, and applies to the rest of the module it's
+/// used in and any modules declared after it in there, in the same way as `macro_rules!` macros.
+/// Any of the macros taking locks then takes them in this order when given `order = hierarchy;`,
+/// and it's a compile error for one of their locks to not be in the hierarchy. Locks are matched
+/// by the name their guard is bound to, which includes any `as` alias.
+///
+/// ```
+/// use lock_order::{lock, lock_hierarchy};
+/// use std::sync::Mutex;
+///
+/// lock_hierarchy!(registry < state < queue);
+///
+/// let registry = Mutex::new(1);
+/// let state = Mutex::new(2);
+/// let queue = Mutex::new(3);
+/// {
+///     // Takes `registry`, then `state`, then `queue`.
+///     lock!(order = hierarchy; mut queue, state, registry);
+///     *queue += *state + *registry;
+/// }
+/// ```
+///
+/// Unlike sorting by name, this orders locks that are never taken together at the same call site,
+/// so that taking `queue` while holding `registry` and `registry` while holding `queue` can't
+/// both happen if every call site uses the hierarchy.
+#[proc_macro]
+pub fn lock_hierarchy(item: TokenStream) -> TokenStream {
+    match hierarchy::declare(item) {
+        Ok(declaration) => declaration,
+        Err(error) => error.to_compile_error_items(),
+    }
+}
+
+/// The re-entry point from the `lock_hierarchy!` in scope, given its hierarchy along with the
+/// macro and locks that were deferred to it.
+#[doc(hidden)]
+#[proc_macro]
+pub fn __lock_ranked(item: TokenStream) -> TokenStream {
+    let expanded = hierarchy::resume(item).and_then(|(mac, ranks, item)| {
+        let mut invocation = mac.parse(item)?;
+        hierarchy::rank(&mut invocation.locks, &ranks)?;
+        Ok(expand_parsed(mac, invocation))
+    });
+    match expanded {
+        Ok(expanded) => expanded,
+        Err(error) => error.to_compile_error(),
+    }
+}
+
+/// Derive the ranks of a struct's locks from `#[lock_rank(N)]` attributes on its fields, for
+/// `order = rank;`.
+///
+/// Locks which are fields of the struct are then taken lowest rank first by any of the macros
+/// taking locks when given `order = rank;`, with locks of the same rank sorted by name. Every lock
+/// must be a field with a rank, and a field without one is a compile error about a missing
+/// `__lock_rank_<field>` method.
+///
+/// ```
+/// use lock_order::{lock, LockRanks};
+/// use std::sync::Mutex;
+///
+/// #[derive(LockRanks)]
+/// struct Locks {
+///     #[lock_rank(10)]
+///     sessions: Mutex<u32>,
+///     #[lock_rank(20)]
+///     connections: Mutex<u32>,
+/// }
+///
+/// let locks = Locks {
+///     sessions: Mutex::new(1),
+///     connections: Mutex::new(2),
+/// };
+/// // Takes `sessions`, then `connections`.
+/// lock!(order = rank; mut locks.connections, locks.sessions);
+/// *connections += *sessions;
+/// ```
+///
+/// As the ranks are only known once the types of the locks are, they are compared when the locks
+/// are taken rather than when the macro is expanded.
+#[proc_macro_derive(LockRanks, attributes(lock_rank))]
+pub fn derive_lock_ranks(item: TokenStream) -> TokenStream {
+    match ranks::derive(item) {
+        Ok(ranks) => ranks,
+        Err(error) => error.to_compile_error_items(),
+    }
+}
+
+/// The macros taking a set of locks.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Macro {
+    Lock,
+    AsyncLock,
+    TryLock,
+    LockMore,
+    WaitLocked,
+}
+
+impl Macro {
+    fn name(self) -> &'static str {
+        match self {
+            Macro::Lock => "lock",
+            Macro::AsyncLock => "async_lock",
+            Macro::TryLock => "try_lock",
+            Macro::LockMore => "lock_more",
+            Macro::WaitLocked => "wait_locked",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        [
+            Macro::Lock,
+            Macro::AsyncLock,
+            Macro::TryLock,
+            Macro::LockMore,
+            Macro::WaitLocked,
+        ]
+        .iter()
+        .copied()
+        .find(|mac| mac.name() == name)
+    }
+
+    fn backend(self) -> Backend {
+        match self {
+            Macro::AsyncLock => Backend::Tokio,
+            Macro::Lock | Macro::TryLock | Macro::LockMore | Macro::WaitLocked => Backend::Std,
+        }
+    }
+
+    /// Parse everything given to the macro.
+    fn parse(self, item: TokenStream) -> Result<Invocation, Error> {
+        match self {
+            Macro::WaitLocked => wait::parse_wait(item),
+            _ => parse(item, self.backend(), self.kind()),
+        }
+    }
+
+    fn kind(self) -> Kind {
+        match self {
+            Macro::TryLock => Kind::TryLock,
+            Macro::Lock | Macro::AsyncLock | Macro::LockMore | Macro::WaitLocked => Kind::Lock,
+        }
+    }
+}
+
+fn expand(item: TokenStream, mac: Macro) -> TokenStream {
+    let mut invocation = match mac.parse(item.clone()) {
+        Ok(invocation) => invocation,
+        Err(error) => return error.to_compile_error(),
+    };
+    let manifest = match &invocation.options.order {
+        // The ranks are only known to the `lock_hierarchy!` in scope, so hand the locks over to
+        // it to come back with them.
+        Order::Hierarchy => return hierarchy::defer(mac, item),
+        Order::Manifest(section) => match manifest::rank(&mut invocation.locks, section.as_ref()) {
+            Ok(manifest) => manifest,
+            Err(error) => return error.to_compile_error(),
+        },
+        Order::Binding
+        | Order::Field
+        | Order::Path
+        | Order::Natural
+        | Order::Rank
+        | Order::Address => return expand_parsed(mac, invocation),
+    };
+    let expanded = expand_parsed(mac, invocation);
+    match mac.kind() {
+        Kind::Lock => quote("$0 $1", &[manifest, expanded]),
+        Kind::TryLock => quote("{ $0 $1 }", &[manifest, expanded]),
+    }
+}
+
+fn expand_parsed(mac: Macro, mut invocation: Invocation) -> TokenStream {
+    if let Some(wait) = invocation.wait.take() {
+        let lock = expand_parsed(Macro::Lock, invocation.clone());
+        return wait::expand(&wait, &invocation, lock)
+            .unwrap_or_else(|error| error.to_compile_error());
+    }
+    if mac == Macro::LockMore {
+        return match ranks::after_held(&invocation) {
+            Ok(check) => quote("$0 $1", &[check, expand_parsed(Macro::Lock, invocation)]),
+            Err(error) => error.to_compile_error(),
+        };
+    }
+    if let Some(token) = invocation.options.level.clone() {
+        return ranks::lock_at_level(invocation, &token)
+            .unwrap_or_else(|error| error.to_compile_error());
+    }
+    let ranked = match invocation.options.order {
+        Order::Rank | Order::Address => match mac.kind() {
+            Kind::Lock => ranks::lock_with(invocation),
+            Kind::TryLock => ranks::try_lock_with(invocation),
+        },
+        _ => match mac.kind() {
+            Kind::Lock => return lock_with(invocation),
+            Kind::TryLock => return try_lock_with(invocation),
+        },
+    };
+    ranked.unwrap_or_else(|error| error.to_compile_error())
+}
+
+fn try_lock_with(invocation: Invocation) -> TokenStream {
+    let locks = &invocation.locks;
+    let options = &invocation.options;
+
+    let guard = |i: usize| quote(&format!("__lock_order_{}", i), &[]);
+    let mut attempts = TokenStream::new();
+    for i in options.order.sorted(locks) {
+        attempts.extend(quote(
+            "let $0 = match $1 { Some(guard) => guard, None => break 'try_lock None };",
+            &[
+                guard(i),
+                locks[i].try_acquire(invocation.backend, options.poison),
+            ],
+        ));
+    }
+    let guards = separated((0..locks.len()).map(guard));
+
+    quote(
+        "'try_lock: { $0 Some($1) }",
+        &[attempts, parenthesised(guards)],
+    )
+}
+
+fn lock_with(invocation: Invocation) -> TokenStream {
+    let Invocation {
+        backend,
+        options,
+        locks,
+        ..
+    } = invocation;
+    let locks: Vec<LockItem> = options
+        .order
+        .sorted(&locks)
+        .into_iter()
+        .map(|i| locks[i].clone())
+        .collect();
+
+    let declarations = separated(locks.iter().map(|x| x.pattern()));
+    let acquisitions = separated(locks.iter().map(|x| x.acquire_with(backend, &options)));
+
+    // A guard is often only there to hold its lock rather than to be used, and the bindings
+    // keep the spans they were written with so would otherwise be linted like any other `let`.
+    quote(
+        "$0 #[allow(unused_mut, unused_variables)] let $1 = $2;",
+        &[
+            options.deadline(),
+            parenthesised(declarations),
+            parenthesised(acquisitions),
+        ],
+    )
+}
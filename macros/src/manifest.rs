@@ -0,0 +1,200 @@
+use crate::item::LockItem;
+use crate::quote::{quote, string, Error};
+use proc_macro::{Span, TokenStream};
+use std::path::PathBuf;
+
+/// The file ranking the locks of a whole crate, next to its `Cargo.toml`.
+const FILE: &str = "lock_order.toml";
+
+/// A `[modules.<path>]` section of the manifest, selected with `order = manifest(<path>);`.
+#[derive(Clone, Debug)]
+pub(crate) struct Section {
+    pub(crate) name: String,
+    pub(crate) span: Span,
+}
+
+/// A single `name = rank` entry.
+struct Rank {
+    /// The `[modules.<path>]` section it's in, or `None` for `[ranks]`.
+    section: Option<String>,
+    name: String,
+    rank: u64,
+}
+
+/// Where the manifest of the crate being compiled would be.
+fn path() -> Option<PathBuf> {
+    std::env::var_os("CARGO_MANIFEST_DIR").map(|dir| PathBuf::from(dir).join(FILE))
+}
+
+/// Whether the crate being compiled has a manifest, which then orders locks by default.
+///
+/// Only a manifest which exists is included in the expansion, so adding or removing one isn't
+/// seen until something else rebuilds the crate, as the crate docs say.
+pub(crate) fn exists() -> bool {
+    path().is_some_and(|path| path.is_file())
+}
+
+/// Give each of `locks` its rank from the manifest, looking in `section` before `[ranks]` and by
+/// the whole lock before the name its guard is bound to.
+///
+/// This returns the tokens including the manifest in the expansion, so that the crate is rebuilt
+/// when it changes.
+pub(crate) fn rank(
+    locks: &mut [LockItem],
+    section: Option<&Section>,
+) -> Result<TokenStream, Error> {
+    let path = match path().filter(|path| path.is_file()) {
+        Some(path) => path,
+        None => {
+            return Err(Error::new(
+                section.map_or_else(Span::call_site, |s| s.span),
+                format!("`order = manifest` needs a {} next to Cargo.toml", FILE),
+            ))
+        }
+    };
+    let source = std::fs::read_to_string(&path).map_err(|e| {
+        Error::new(
+            Span::call_site(),
+            format!("can't read {}: {}", path.display(), e),
+        )
+    })?;
+    let ranks = parse(&source).map_err(|(line, message)| {
+        Error::new(Span::call_site(), format!("{}:{}: {}", FILE, line, message))
+    })?;
+
+    if let Some(section) = section {
+        if !ranks
+            .iter()
+            .any(|r| r.section.as_ref() == Some(&section.name))
+        {
+            return Err(Error::new(
+                section.span,
+                format!("{} has no `[modules.\"{}\"]` section", FILE, section.name),
+            ));
+        }
+    }
+    let section = section.map(|s| &s.name);
+    for lock in locks.iter_mut().filter(|lock| lock.pinned.is_none()) {
+        let names = [lock.full_identifier.clone(), lock.binding_name()];
+        let found = [section, None].iter().find_map(|scope| {
+            names.iter().find_map(|name| {
+                ranks
+                    .iter()
+                    .find(|r| r.section.as_ref() == *scope && &r.name == name)
+            })
+        });
+        match found {
+            Some(found) => lock.rank = Some(found.rank),
+            None => {
+                return Err(Error::new(
+                    lock.span(),
+                    format!("`{}` has no rank in {}", lock.full_identifier, FILE),
+                ))
+            }
+        }
+    }
+
+    Ok(quote(
+        "const _: &str = ::core::include_str!($0);",
+        &[string(&path.to_string_lossy())],
+    ))
+}
+
+/// Parse the small part of TOML the manifest uses, giving the line and message of any error.
+///
+/// ```toml
+/// # Ranks for the whole crate, lowest first.
+/// [ranks]
+/// registry = 10
+/// "self.locks.connections" = 20
+///
+/// # Ranks for `order = manifest(net::sessions);`, as well as those in `[ranks]`.
+/// [modules."net::sessions"]
+/// sessions = 15
+/// ```
+fn parse(source: &str) -> Result<Vec<Rank>, (usize, String)> {
+    let mut ranks: Vec<Rank> = Vec::new();
+    let mut section = None;
+    for (i, line) in source.lines().enumerate() {
+        let error = |message: String| (i + 1, message);
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(header) = line.strip_prefix('[') {
+            let header = header
+                .strip_suffix(']')
+                .ok_or_else(|| error("expected `]` to close the section".to_string()))?
+                .trim();
+            section = Some(if header == "ranks" {
+                None
+            } else if let Some(module) = header.strip_prefix("modules.") {
+                let (module, rest) = key(module.trim()).map_err(error)?;
+                if !rest.trim().is_empty() {
+                    return Err(error(format!("unexpected `{}` in section", rest.trim())));
+                }
+                Some(module)
+            } else {
+                return Err(error(format!(
+                    "unknown section `[{}]`, expected `[ranks]` or `[modules.<path>]`",
+                    header
+                )));
+            });
+            continue;
+        }
+
+        let section = section.clone().ok_or_else(|| {
+            error("expected `[ranks]` or `[modules.<path>]` before any ranks".to_string())
+        })?;
+        let (name, rest) = key(line).map_err(error)?;
+        let value = rest
+            .trim_start()
+            .strip_prefix('=')
+            .ok_or_else(|| error(format!("expected `=` after `{}`", name)))?
+            .trim();
+        let rank = value
+            .replace('_', "")
+            .parse()
+            .map_err(|_| error(format!("expected a rank for `{}`, not `{}`", name, value)))?;
+        if ranks.iter().any(|r| r.section == section && r.name == name) {
+            return Err(error(format!("`{}` is ranked more than once", name)));
+        }
+        ranks.push(Rank {
+            section,
+            name,
+            rank,
+        });
+    }
+    Ok(ranks)
+}
+
+/// Parse a bare or `"quoted"` key from the start of `line`, returning it and the rest of the line.
+fn key(line: &str) -> Result<(String, &str), String> {
+    if let Some(quoted) = line.strip_prefix('"') {
+        let end = quoted
+            .find('"')
+            .ok_or_else(|| "expected `\"` to close the name".to_string())?;
+        return Ok((quoted[..end].to_string(), &quoted[end + 1..]));
+    }
+    let end = line
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '-'))
+        .unwrap_or(line.len());
+    if end == 0 {
+        return Err(format!("expected a name, not `{}`", line));
+    }
+    Ok((line[..end].to_string(), &line[end..]))
+}
+
+/// `line` without any `#` comment, leaving a `#` in a quoted name alone.
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '#' if !quoted => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
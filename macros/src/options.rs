@@ -0,0 +1,325 @@
+use crate::item::LockItem;
+use crate::manifest::Section;
+use crate::quote::{quote, Error};
+use proc_macro::{Delimiter, Ident, Span, TokenStream, TokenTree};
+
+/// Which flavour of lock is being used, selected with a `backend:` prefix to the locks.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub(crate) enum Backend {
+    /// `std::sync`, whose locks poison and so need unwrapping.
+    #[default]
+    Std,
+    /// `parking_lot`, whose locks don't poison and return the guard directly.
+    ParkingLot,
+    /// `tokio::sync`, whose locks return a future of the guard.
+    Tokio,
+}
+
+impl Backend {
+    pub(crate) fn from_ident(name: &Ident) -> Result<Self, Error> {
+        match name.to_string().as_str() {
+            "std" => Ok(Backend::Std),
+            "parking_lot" => Ok(Backend::ParkingLot),
+            "tokio" => Ok(Backend::Tokio),
+            other => Err(Error::new(
+                name.span(),
+                format!(
+                    "unknown lock backend `{}`, expected `std`, `parking_lot` or `tokio`",
+                    other
+                ),
+            )),
+        }
+    }
+}
+
+/// What to do with a lock poisoned by a thread panicking while holding it, `poison = ...;`.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub(crate) enum Poison {
+    /// Carry on panicking in this thread too.
+    #[default]
+    Panic,
+    /// Take the guard from the poisoned lock anyway.
+    Recover,
+    /// Return a `lock_order::PoisonError` with `?`.
+    Propagate,
+}
+
+impl Poison {
+    fn from_value(value: &TokenStream, span: Span) -> Result<Self, Error> {
+        match value.to_string().as_str() {
+            "panic" => Ok(Poison::Panic),
+            "recover" => Ok(Poison::Recover),
+            "propagate" => Ok(Poison::Propagate),
+            other => Err(Error::new(
+                span,
+                format!(
+                    "unknown poison policy `{}`, expected `panic`, `recover` or `propagate`",
+                    other
+                ),
+            )),
+        }
+    }
+
+    /// The expression turning `result`, a `LockResult` for `lock`, into its guard.
+    pub(crate) fn unwrap(self, result: TokenStream, lock: &LockItem) -> TokenStream {
+        match self {
+            Poison::Panic => quote("$0.unwrap()", &[result]),
+            Poison::Recover => quote(
+                "$0.unwrap_or_else(::std::sync::PoisonError::into_inner)",
+                &[result],
+            ),
+            Poison::Propagate => quote(
+                "match $0 { Ok(guard) => guard, Err(_) => $1 }",
+                &[result, Self::propagate(lock)],
+            ),
+        }
+    }
+
+    /// The expression handling `e`, a `PoisonError` for `lock`, when trying a lock and evaluating
+    /// to an `Option` of its guard.
+    pub(crate) fn try_unwrap(self, lock: &LockItem) -> TokenStream {
+        match self {
+            Poison::Panic => quote("panic!(\"{}\", e)", &[]),
+            Poison::Recover => quote("Some(e.into_inner())", &[]),
+            Poison::Propagate => Self::propagate(lock),
+        }
+    }
+
+    fn propagate(lock: &LockItem) -> TokenStream {
+        quote("Err(::lock_order::PoisonError::new($0))?", &[lock.name()])
+    }
+}
+
+/// What the locks are sorted by, `order = ...;`.
+#[derive(Clone, Debug, Default)]
+pub(crate) enum Order {
+    /// The name of the bound variable, which is the `as` alias if there is one.
+    #[default]
+    Binding,
+    /// The last identifier of the lock itself, ignoring any `as` alias.
+    Field,
+    /// The whole lock as written, as in `self.a.state`, ignoring any `as` alias.
+    Path,
+    /// The name of the bound variable, comparing runs of digits as numbers so `lock2` comes
+    /// before `lock10`.
+    Natural,
+    /// The rank of the bound variable's name in the `lock_hierarchy!` in scope.
+    Hierarchy,
+    /// The rank of the lock in the crate's `lock_order.toml`, looking in the given section too.
+    Manifest(Option<Section>),
+    /// The `#[lock_rank(N)]` of each lock's field, compared when the locks are taken.
+    Rank,
+    /// The address of each lock, compared when the locks are taken.
+    Address,
+}
+
+impl Order {
+    fn from_value(value: &TokenStream, span: Span) -> Result<Self, Error> {
+        let mut tokens = value.clone().into_iter();
+        match (tokens.next(), tokens.next(), tokens.next()) {
+            (Some(TokenTree::Ident(name)), Some(TokenTree::Group(section)), None)
+                if name.to_string() == "manifest"
+                    && section.delimiter() == Delimiter::Parenthesis =>
+            {
+                let name: String = section
+                    .stream()
+                    .into_iter()
+                    .map(|t| t.to_string())
+                    .collect();
+                if name.is_empty() {
+                    return Err(Error::new(
+                        section.span(),
+                        "expected a module path for the manifest section, as in `manifest(net)`",
+                    ));
+                }
+                return Ok(Order::Manifest(Some(Section {
+                    name,
+                    span: section.span(),
+                })));
+            }
+            _ => {}
+        }
+        match value.to_string().as_str() {
+            "binding" => Ok(Order::Binding),
+            "field" => Ok(Order::Field),
+            "path" => Ok(Order::Path),
+            "natural" => Ok(Order::Natural),
+            "hierarchy" => Ok(Order::Hierarchy),
+            "manifest" => Ok(Order::Manifest(None)),
+            "rank" => Ok(Order::Rank),
+            "address" => Ok(Order::Address),
+            other => Err(Error::new(
+                span,
+                format!(
+                    "unknown order `{}`, expected `binding`, `field`, `path`, `natural`, \
+                    `hierarchy`, `manifest`, `rank` or `address`",
+                    other
+                ),
+            )),
+        }
+    }
+
+    /// What `lock` is sorted by, its rank if it has one and then its name, with the whole lock as
+    /// written breaking any tie so that every call site agrees on the order.
+    ///
+    /// For `order = rank;` and `order = address;` the order is only known once the locks are
+    /// taken, so this is just the order for ties between them.
+    fn key(&self, lock: &LockItem) -> (Option<u64>, Vec<Part>, String) {
+        let name = match self {
+            Order::Binding
+            | Order::Rank
+            | Order::Address
+            | Order::Hierarchy
+            | Order::Manifest(_) => text(lock.binding_name()),
+            Order::Field => text(lock.field()),
+            Order::Path => text(lock.full_identifier.clone()),
+            Order::Natural => natural(&lock.binding_name()),
+        };
+        (lock.rank, name, lock.full_identifier.clone())
+    }
+
+    /// The indices of `locks` in the order they should be taken in.
+    pub(crate) fn sorted(&self, locks: &[LockItem]) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..locks.len()).collect();
+        order.sort_by_cached_key(|&i| self.key(&locks[i]));
+        order
+    }
+}
+
+/// A run of a name sorted by `order = natural;`, with runs of digits sorting before anything else
+/// as they do in ASCII.
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum Part {
+    /// Digits compared as a number, by their count without leading zeros and then the digits.
+    Number(usize, String),
+    Text(String),
+}
+
+fn text(name: String) -> Vec<Part> {
+    vec![Part::Text(name)]
+}
+
+/// `name` split into runs of digits and of anything else.
+fn natural(name: &str) -> Vec<Part> {
+    let mut parts = Vec::new();
+    let mut rest = name;
+    while let Some(first) = rest.chars().next() {
+        let digit = first.is_ascii_digit();
+        let end = rest
+            .find(|c: char| c.is_ascii_digit() != digit)
+            .unwrap_or(rest.len());
+        let (run, tail) = rest.split_at(end);
+        parts.push(if digit {
+            let number = run.trim_start_matches('0');
+            Part::Number(number.len(), number.to_string())
+        } else {
+            Part::Text(run.to_string())
+        });
+        rest = tail;
+    }
+    parts
+}
+
+/// Settings given as `name = value;` before the locks.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Options {
+    /// `timeout = <Duration>;`, the time allowed for taking the whole set of locks.
+    pub(crate) timeout: Option<TokenStream>,
+    /// `poison = panic | recover | propagate;`, only for `std` locks.
+    pub(crate) poison: Poison,
+    /// `order = binding | field | path | natural | hierarchy | manifest[(<path>)] | rank
+    /// | address;`
+    pub(crate) order: Order,
+    /// `level = <token>;`, the variable holding the `Level` token for `OrderedMutex` and
+    /// `OrderedRwLock` locks.
+    pub(crate) level: Option<Ident>,
+}
+
+impl Options {
+    /// The statement starting the clock for a `timeout`, if one was given.
+    pub(crate) fn deadline(&self) -> TokenStream {
+        match &self.timeout {
+            Some(timeout) => quote(
+                "let __lock_order_deadline = ::std::time::Instant::now() + ($0);",
+                std::slice::from_ref(timeout),
+            ),
+            None => TokenStream::new(),
+        }
+    }
+
+    /// Set the option called `name` to `value`, checking it makes sense for `backend` and that it
+    /// hasn't already been given.
+    pub(crate) fn set(
+        &mut self,
+        name: &Ident,
+        value: TokenStream,
+        backend: Backend,
+        seen: &mut Vec<String>,
+    ) -> Result<(), Error> {
+        let key = name.to_string();
+        if seen.contains(&key) {
+            return Err(Error::new(
+                name.span(),
+                format!("the `{}` option is given more than once", key),
+            ));
+        }
+        let span = value
+            .clone()
+            .into_iter()
+            .next()
+            .as_ref()
+            .map_or(name.span(), TokenTree::span);
+        match key.as_str() {
+            "timeout" => {
+                if backend == Backend::Std {
+                    return Err(Error::new(
+                        name.span(),
+                        "std locks can't time out, use `parking_lot:` or `tokio:` locks",
+                    ));
+                }
+                self.timeout = Some(value);
+            }
+            "poison" => {
+                if backend != Backend::Std {
+                    return Err(Error::new(name.span(), "only std locks can be poisoned"));
+                }
+                self.poison = Poison::from_value(&value, span)?;
+            }
+            "order" => {
+                self.order = Order::from_value(&value, span)?;
+                if matches!(self.order, Order::Address) && backend != Backend::Std {
+                    return Err(Error::new(span, "only std locks can be ordered by address"));
+                }
+            }
+            "level" => {
+                if backend != Backend::Std {
+                    return Err(Error::new(
+                        name.span(),
+                        "only std locks have levels, with `OrderedMutex` and `OrderedRwLock`",
+                    ));
+                }
+                let mut tokens = value.into_iter();
+                match (tokens.next(), tokens.next()) {
+                    (Some(TokenTree::Ident(token)), None) => self.level = Some(token),
+                    _ => {
+                        return Err(Error::new(
+                            span,
+                            "expected the name of the variable holding the `Level` token",
+                        ))
+                    }
+                }
+            }
+            other => {
+                return Err(Error::new(
+                    name.span(),
+                    format!(
+                        "unknown option `{}`, expected `timeout`, `poison`, `order` or `level`",
+                        other
+                    ),
+                ))
+            }
+        }
+        seen.push(key);
+        Ok(())
+    }
+}
@@ -0,0 +1,570 @@
+use crate::item::{LockItem, Mode};
+use crate::manifest;
+use crate::options::{Backend, Options, Order};
+use crate::quote::Error;
+use crate::wait::Wait;
+use proc_macro::{Delimiter, Ident, Spacing, Span, TokenStream, TokenTree};
+
+/// What a macro does with its locks, which decides what it accepts.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum Kind {
+    /// Blocks until the locks are taken and binds their guards to variables.
+    Lock,
+    /// Tries the locks without blocking, evaluating to the guards rather than binding them.
+    TryLock,
+}
+
+/// Everything given to a macro.
+#[derive(Clone)]
+pub(crate) struct Invocation {
+    pub(crate) backend: Backend,
+    pub(crate) options: Options,
+    /// The locks, in the order they were written.
+    pub(crate) locks: Vec<LockItem>,
+    /// What `wait_locked!` waits for while the locks are released.
+    pub(crate) wait: Option<Wait>,
+}
+
+/// The tokens given to a macro, consumed from the front as they're parsed.
+struct Input {
+    tokens: Vec<TokenTree>,
+    position: usize,
+}
+
+impl Input {
+    fn peek(&self) -> Option<&TokenTree> {
+        self.peek_nth(0)
+    }
+
+    fn peek_nth(&self, n: usize) -> Option<&TokenTree> {
+        self.tokens.get(self.position + n)
+    }
+
+    fn next(&mut self) -> Option<TokenTree> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    /// The span of the next token, or of the last one if there's nothing left.
+    fn span(&self) -> Span {
+        self.peek()
+            .or_else(|| self.tokens.last())
+            .map_or_else(Span::call_site, TokenTree::span)
+    }
+}
+
+fn is_punct(token: Option<&TokenTree>, c: char) -> bool {
+    matches!(token, Some(TokenTree::Punct(p)) if p.as_char() == c)
+}
+
+fn is_ident(token: Option<&TokenTree>, name: &str) -> bool {
+    matches!(token, Some(TokenTree::Ident(i)) if i.to_string() == name)
+}
+
+/// Whether `token` could be the start of a lock, rather than carrying on an expression.
+fn starts_lock(token: Option<&TokenTree>) -> bool {
+    match token {
+        Some(TokenTree::Ident(i)) => i.to_string() != "as",
+        Some(TokenTree::Punct(p)) => p.as_char() == '*' || p.as_char() == '&',
+        _ => false,
+    }
+}
+
+/// Parse everything given to a macro of `kind`, using `default` unless another backend is given.
+///
+/// Malformed input is reported at the offending tokens, such as no locks at all:
+///
+/// ```compile_fail
+/// # use lock_order::lock;
+/// lock!();
+/// ```
+///
+/// `mut` after the lock:
+///
+/// ```compile_fail
+/// # use lock_order::lock;
+/// # let a = std::sync::Mutex::new(1);
+/// lock!(a mut);
+/// ```
+///
+/// Trailing or duplicate commas:
+///
+/// ```compile_fail
+/// # use lock_order::lock;
+/// # let a = std::sync::Mutex::new(1);
+/// lock!(a,);
+/// ```
+///
+/// ```compile_fail
+/// # use lock_order::lock;
+/// # let a = std::sync::Mutex::new(1);
+/// # let b = std::sync::Mutex::new(1);
+/// lock!(a,, b);
+/// ```
+///
+/// Tokens which can't be part of a lock:
+///
+/// ```compile_fail
+/// # use lock_order::lock;
+/// # let a = std::sync::Mutex::new(1);
+/// # let b = std::sync::Mutex::new(1);
+/// lock!(a; b);
+/// ```
+///
+/// The same lock twice, or two locks bound to the same name:
+///
+/// ```compile_fail
+/// # use lock_order::lock;
+/// # let a = std::sync::Mutex::new(1);
+/// lock!(a, mut a);
+/// ```
+///
+/// ```compile_fail
+/// # use lock_order::lock;
+/// # struct Pair { inner: std::sync::Mutex<u32> }
+/// # let a = Pair { inner: std::sync::Mutex::new(1) };
+/// # let b = Pair { inner: std::sync::Mutex::new(1) };
+/// lock!(a.inner, b.inner);
+/// ```
+///
+/// Which is reported as the lock being given two ranks when it's ranked differently each time:
+///
+/// ```compile_fail
+/// # use lock_order::lock;
+/// # let a = std::sync::Mutex::new(1);
+/// lock!(#[rank = 1] a, #[rank = 2] a as again);
+/// ```
+///
+/// An attribute other than `#[rank = N]`, or a lock without a rank when others are ranked inline
+/// and the order doesn't rank them:
+///
+/// ```compile_fail
+/// # use lock_order::lock;
+/// # let a = std::sync::Mutex::new(1);
+/// lock!(#[rank(1)] a);
+/// ```
+///
+/// ```compile_fail
+/// # use lock_order::lock;
+/// # let a = std::sync::Mutex::new(1);
+/// # let b = std::sync::Mutex::new(1);
+/// lock!(#[rank = 1] a, b);
+/// ```
+///
+/// Or a lock with nothing to name its guard by:
+///
+/// ```compile_fail
+/// # use lock_order::lock;
+/// # let a = vec![std::sync::Mutex::new(1)];
+/// lock!(a[0]);
+/// ```
+pub(crate) fn parse(item: TokenStream, default: Backend, kind: Kind) -> Result<Invocation, Error> {
+    let mut input = Input {
+        tokens: item.into_iter().collect(),
+        position: 0,
+    };
+    if input.peek().is_none() {
+        return Err(Error::new(Span::call_site(), "expected at least one lock"));
+    }
+
+    let backend = parse_backend(&mut input, default)?;
+    let options = parse_options(&mut input, backend, kind)?;
+
+    let mut locks = Vec::new();
+    loop {
+        if let Some(comma) = input.peek().filter(|t| is_punct(Some(t), ',')) {
+            let message = if locks.is_empty() {
+                "expected a lock before `,`"
+            } else {
+                "duplicate `,`"
+            };
+            return Err(Error::new(comma.span(), message));
+        }
+        locks.push(parse_lock(&mut input, kind)?);
+        match input.next() {
+            None => break,
+            Some(comma) => {
+                if input.peek().is_none() {
+                    return Err(Error::new(comma.span(), "unexpected trailing `,`"));
+                }
+            }
+        }
+    }
+
+    check_duplicates(&locks, kind)?;
+    check_ranks(&options, &locks)?;
+
+    Ok(Invocation {
+        backend,
+        options,
+        locks,
+        wait: None,
+    })
+}
+
+/// Parse the guards given to `unlock!`, which are names separated by `,`.
+pub(crate) fn parse_guards(item: TokenStream) -> Result<Vec<Ident>, Error> {
+    let mut input = Input {
+        tokens: item.into_iter().collect(),
+        position: 0,
+    };
+    if input.peek().is_none() {
+        return Err(Error::new(Span::call_site(), "expected at least one guard"));
+    }
+    let mut guards = Vec::new();
+    loop {
+        match input.next() {
+            Some(TokenTree::Ident(guard)) => guards.push(guard),
+            token => {
+                let span = token.map_or_else(|| input.span(), |t| t.span());
+                return Err(Error::new(
+                    span,
+                    "expected the name of a guard bound by `lock!`",
+                ));
+            }
+        }
+        match input.next() {
+            None => return Ok(guards),
+            Some(comma) if is_punct(Some(&comma), ',') && input.peek().is_some() => {}
+            Some(token) => return Err(Error::new(token.span(), "expected `,` between guards")),
+        }
+    }
+}
+
+/// Split the input to `with_locks!` at its `=>`, into the locks and the body run while holding
+/// them.
+pub(crate) fn split_body(item: TokenStream) -> Result<(TokenStream, TokenStream), Error> {
+    let tokens: Vec<TokenTree> = item.into_iter().collect();
+    let arrow = tokens.windows(2).position(|pair| match pair {
+        [TokenTree::Punct(eq), TokenTree::Punct(gt)] => {
+            eq.as_char() == '=' && eq.spacing() == Spacing::Joint && gt.as_char() == '>'
+        }
+        _ => false,
+    });
+    let arrow = match arrow {
+        Some(arrow) => arrow,
+        None => {
+            let span = tokens.last().map_or_else(Span::call_site, TokenTree::span);
+            return Err(Error::new(span, "expected `=>` and a body after the locks"));
+        }
+    };
+    if arrow + 2 == tokens.len() {
+        return Err(Error::new(
+            tokens[arrow + 1].span(),
+            "expected a body after `=>`",
+        ));
+    }
+    Ok((
+        tokens[..arrow].iter().cloned().collect(),
+        tokens[arrow + 2..].iter().cloned().collect(),
+    ))
+}
+
+/// Reject the same lock being given twice, which would deadlock, whether or not it's given two
+/// different ranks, and two guards being bound to the same name.
+fn check_duplicates(locks: &[LockItem], kind: Kind) -> Result<(), Error> {
+    for (i, lock) in locks.iter().enumerate() {
+        for earlier in &locks[..i] {
+            if lock.full_identifier == earlier.full_identifier {
+                // Giving the lock two ranks is the clearer mistake to report.
+                if let (Some((rank, pinned)), Some((earlier_rank, earlier_pinned))) =
+                    (lock.rank.zip(lock.pinned), earlier.rank.zip(earlier.pinned))
+                {
+                    if rank != earlier_rank {
+                        return Err(Error::new(
+                            pinned,
+                            format!(
+                                "`{}` is ranked {} here, but {} where it's first locked",
+                                lock.full_identifier, rank, earlier_rank
+                            ),
+                        )
+                        .note(
+                            earlier_pinned,
+                            format!("`{}` is first ranked here", earlier.full_identifier),
+                        ));
+                    }
+                }
+                return Err(Error::new(
+                    lock.span(),
+                    format!(
+                        "`{}` is locked more than once, which would deadlock",
+                        lock.full_identifier
+                    ),
+                )
+                .note(
+                    earlier.span(),
+                    format!("`{}` is first locked here", earlier.full_identifier),
+                ));
+            }
+            if kind == Kind::Lock && lock.binding_name() == earlier.binding_name() {
+                return Err(Error::new(
+                    lock.binding_span(),
+                    format!(
+                        "`{}` is bound by more than one lock, name one of them with `as`",
+                        lock.binding_name()
+                    ),
+                )
+                .note(
+                    earlier.binding_span(),
+                    format!(
+                        "`{}` is first bound here by `{}`",
+                        earlier.binding_name(),
+                        earlier.full_identifier
+                    ),
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check that locks ranked inline with `#[rank = N]` are taken in an order that has ranks, and
+/// that the name orders have a rank for every lock.
+fn check_ranks(options: &Options, locks: &[LockItem]) -> Result<(), Error> {
+    let pinned = match locks.iter().find_map(|lock| lock.pinned) {
+        Some(pinned) => pinned,
+        None => return Ok(()),
+    };
+    if options.level.is_some() {
+        return Err(Error::new(
+            pinned,
+            "locks with a `level` are taken in the order of their levels, \
+            so can't be ranked inline",
+        ));
+    }
+    match options.order {
+        Order::Address => Err(Error::new(
+            pinned,
+            "locks taken by address can't be ranked inline",
+        )),
+        Order::Binding | Order::Field | Order::Path | Order::Natural => {
+            match locks.iter().find(|lock| lock.pinned.is_none()) {
+                Some(lock) => Err(Error::new(
+                    lock.span(),
+                    format!(
+                        "`{}` needs a `#[rank = N]` too, as other locks here are ranked inline",
+                        lock.full_identifier
+                    ),
+                )
+                .note(pinned, "a lock is ranked inline here")),
+                None => Ok(()),
+            }
+        }
+        Order::Hierarchy | Order::Manifest(_) | Order::Rank => Ok(()),
+    }
+}
+
+/// Parse a leading `std:`, `parking_lot:` or `tokio:` backend selector, if there is one.
+fn parse_backend(input: &mut Input, default: Backend) -> Result<Backend, Error> {
+    let selector = matches!(
+        (input.peek(), input.peek_nth(1)),
+        (Some(TokenTree::Ident(_)), Some(TokenTree::Punct(colon)))
+            if colon.as_char() == ':' && colon.spacing() == Spacing::Alone
+    );
+    if !selector {
+        return Ok(default);
+    }
+    let backend = match input.next() {
+        Some(TokenTree::Ident(name)) => Backend::from_ident(&name)?,
+        _ => unreachable!(),
+    };
+    input.next();
+    Ok(backend)
+}
+
+/// Parse any leading `name = value;` options.
+fn parse_options(input: &mut Input, backend: Backend, kind: Kind) -> Result<Options, Error> {
+    let mut options = Options::default();
+    let mut seen = Vec::new();
+    loop {
+        let name = match (input.peek(), input.peek_nth(1)) {
+            (Some(TokenTree::Ident(name)), Some(TokenTree::Punct(eq)))
+                if eq.as_char() == '=' && eq.spacing() == Spacing::Alone =>
+            {
+                name.clone()
+            }
+            _ => break,
+        };
+        input.next();
+        let eq = input.next().map(|t| t.span());
+
+        let mut value = TokenStream::new();
+        loop {
+            match input.next() {
+                Some(TokenTree::Punct(p)) if p.as_char() == ';' => break,
+                Some(token) => value.extend(Some(token)),
+                None => {
+                    return Err(Error::new(
+                        name.span(),
+                        format!("expected `;` after the `{}` option", name),
+                    ))
+                }
+            }
+        }
+        if value.is_empty() {
+            return Err(Error::new(
+                eq.unwrap_or_else(|| name.span()),
+                format!("expected a value for the `{}` option", name),
+            ));
+        }
+        if kind == Kind::TryLock && name.to_string() == "timeout" {
+            return Err(Error::new(
+                name.span(),
+                "try_lock! doesn't wait, so can't take a timeout",
+            ));
+        }
+        if kind == Kind::TryLock && name.to_string() == "level" {
+            return Err(Error::new(
+                name.span(),
+                "try_lock! doesn't bind a new token, so can't take a level",
+            ));
+        }
+        options.set(&name, value, backend, &mut seen)?;
+    }
+    let ordered = seen.iter().any(|name| name == "order");
+    if let (true, Some(level)) = (ordered, &options.level) {
+        return Err(Error::new(
+            level.span(),
+            "locks with a `level` are taken in the order of their levels, so can't have an `order`",
+        ));
+    }
+    // Without an `order`, the crate's `lock_order.toml` decides the order if it has one.
+    if !ordered && options.level.is_none() && manifest::exists() {
+        options.order = Order::Manifest(None);
+    }
+    Ok(options)
+}
+
+/// Parse a `#[rank = N]` before a lock, if there is one, into the rank and where it was given.
+fn parse_rank(input: &mut Input) -> Result<Option<(u64, Span)>, Error> {
+    if !is_punct(input.peek(), '#') {
+        return Ok(None);
+    }
+    let hash = input.next().unwrap();
+    let attribute = match input.next() {
+        Some(TokenTree::Group(group)) if group.delimiter() == Delimiter::Bracket => group,
+        _ => {
+            return Err(Error::new(
+                hash.span(),
+                "expected `#[rank = N]` before the lock",
+            ))
+        }
+    };
+    let tokens: Vec<TokenTree> = attribute.stream().into_iter().collect();
+    match tokens.as_slice() {
+        [TokenTree::Ident(name), TokenTree::Punct(eq), TokenTree::Literal(rank)]
+            if name.to_string() == "rank" && eq.as_char() == '=' =>
+        {
+            match rank.to_string().parse::<u64>() {
+                Ok(value) => Ok(Some((value, rank.span()))),
+                Err(_) => Err(Error::new(
+                    rank.span(),
+                    "expected an integer rank, as in `#[rank = 1]`",
+                )),
+            }
+        }
+        [TokenTree::Ident(name), ..] if name.to_string() == "rank" => Err(Error::new(
+            attribute.span(),
+            "expected an integer rank, as in `#[rank = 1]`",
+        )),
+        _ => Err(Error::new(
+            attribute.span(),
+            "expected `#[rank = N]`, the only attribute a lock can have",
+        )),
+    }
+}
+
+/// Parse a single lock, up to the `,` after it or the end of the input.
+fn parse_lock(input: &mut Input, kind: Kind) -> Result<LockItem, Error> {
+    let mut lock = LockItem::default();
+
+    if let Some((rank, span)) = parse_rank(input)? {
+        lock.rank = Some(rank);
+        lock.pinned = Some(span);
+    }
+
+    // `read` and `write` are only modes when followed by the lock itself, otherwise they are just
+    // a lock that happens to be called `read` or `write`.
+    if let Some(TokenTree::Ident(id)) = input.peek() {
+        if let Some(mode) = Mode::from_name(&id.to_string()) {
+            if starts_lock(input.peek_nth(1)) {
+                lock.mode = mode;
+                input.next();
+            }
+        }
+    }
+
+    if let Some(TokenTree::Ident(id)) = input.peek() {
+        if id.to_string() == "mut" {
+            lock.mutable = Some(id.clone());
+            input.next();
+        }
+    }
+
+    while let Some(token) = input.peek() {
+        match token {
+            TokenTree::Punct(p) if p.as_char() == ',' => break,
+            TokenTree::Ident(id) if id.to_string() == "as" => break,
+            TokenTree::Ident(id) if id.to_string() == "mut" => {
+                return Err(Error::new(
+                    id.span(),
+                    "`mut` must come before the lock, as in `mut self.lock`",
+                ));
+            }
+            TokenTree::Punct(p) if matches!(p.as_char(), ';' | '=' | '#' | '$' | '@' | '~') => {
+                return Err(Error::new(
+                    p.span(),
+                    format!("unexpected `{}` in a lock", p.as_char()),
+                ));
+            }
+            _ => {
+                let token = input.next().unwrap();
+                lock.add(token);
+            }
+        }
+    }
+
+    if lock.expression.is_empty() {
+        return Err(Error::new(input.span(), "expected a lock"));
+    }
+
+    if is_ident(input.peek(), "as") {
+        let as_span = input.next().unwrap().span();
+        match input.next() {
+            Some(TokenTree::Ident(alias)) => lock.alias = Some(alias),
+            Some(other) => {
+                return Err(Error::new(
+                    other.span(),
+                    "expected a name to bind the lock to after `as`",
+                ))
+            }
+            None => {
+                return Err(Error::new(
+                    as_span,
+                    "expected a name to bind the lock to after `as`",
+                ))
+            }
+        }
+    }
+
+    match input.peek() {
+        None => {}
+        Some(token) if is_punct(Some(token), ',') => {}
+        Some(token) => {
+            return Err(Error::new(token.span(), "expected `,` between locks"));
+        }
+    }
+
+    if kind == Kind::Lock && lock.binding().is_none() {
+        return Err(Error::new(
+            lock.span(),
+            format!(
+                "can't name the guard for `{}`, give it a name with `as`",
+                lock.full_identifier
+            ),
+        ));
+    }
+
+    Ok(lock)
+}
@@ -0,0 +1,136 @@
+use proc_macro::{Delimiter, Group, Ident, Literal, Punct, Spacing, Span, TokenStream, TokenTree};
+
+/// Build the tokens for `template`, replacing each `$0`, `$1`, ... with the matching entry of
+/// `args`, and each `$$` with a single `$`.
+///
+/// The template itself is only ever written in this crate, so failing to parse it is a bug here
+/// rather than in the macro's input. The template's own tokens get the call site span, while the
+/// arguments keep whatever spans they came with.
+pub(crate) fn quote(template: &str, args: &[TokenStream]) -> TokenStream {
+    let tokens: TokenStream = template
+        .parse()
+        .unwrap_or_else(|e| panic!("invalid template `{}`: {}", template, e));
+    substitute(tokens, args)
+}
+
+fn substitute(tokens: TokenStream, args: &[TokenStream]) -> TokenStream {
+    let mut out = TokenStream::new();
+    let mut tokens = tokens.into_iter();
+    while let Some(token) = tokens.next() {
+        match token {
+            TokenTree::Punct(ref p) if p.as_char() == '$' => {
+                let index = match tokens.next() {
+                    // `$$` is a `$` of the template's own, such as in a `macro_rules!` it defines.
+                    Some(TokenTree::Punct(dollar)) if dollar.as_char() == '$' => {
+                        out.extend(Some(TokenTree::Punct(dollar)));
+                        continue;
+                    }
+                    Some(TokenTree::Literal(index)) => index.to_string(),
+                    _ => String::new(),
+                };
+                // A method call on a placeholder, as in `$0.lock()`, lexes as the float `0.`.
+                let (index, dot) = match index.strip_suffix('.') {
+                    Some(index) => (index, true),
+                    None => (index.as_str(), false),
+                };
+                match index.parse::<usize>().ok().and_then(|i| args.get(i)) {
+                    Some(arg) => out.extend(arg.clone()),
+                    None => panic!("template placeholder without a matching argument"),
+                }
+                if dot {
+                    out.extend(Some(TokenTree::Punct(Punct::new('.', Spacing::Alone))));
+                }
+            }
+            TokenTree::Group(group) => {
+                let mut substituted =
+                    Group::new(group.delimiter(), substitute(group.stream(), args));
+                substituted.set_span(group.span());
+                out.extend(Some(TokenTree::Group(substituted)));
+            }
+            token => out.extend(Some(token)),
+        }
+    }
+    out
+}
+
+/// Join `items` into a single stream with `,` between each of them.
+pub(crate) fn separated(items: impl IntoIterator<Item = TokenStream>) -> TokenStream {
+    let mut out = TokenStream::new();
+    for (i, item) in items.into_iter().enumerate() {
+        if i > 0 {
+            out.extend(Some(TokenTree::Punct(Punct::new(',', Spacing::Alone))));
+        }
+        out.extend(item);
+    }
+    out
+}
+
+/// A string literal token.
+pub(crate) fn string(value: &str) -> TokenStream {
+    TokenTree::Literal(Literal::string(value)).into()
+}
+
+/// A single identifier, keeping the span it was given.
+pub(crate) fn ident(ident: &Ident) -> TokenStream {
+    TokenTree::Ident(ident.clone()).into()
+}
+
+/// Wrap `tokens` in parentheses.
+pub(crate) fn parenthesised(tokens: TokenStream) -> TokenStream {
+    TokenTree::Group(Group::new(Delimiter::Parenthesis, tokens)).into()
+}
+
+/// An error in the input to a macro, reported at the offending tokens.
+#[derive(Debug)]
+pub(crate) struct Error {
+    /// Each part of the error, the first being the error itself and any others pointing out
+    /// related tokens.
+    messages: Vec<(Span, String)>,
+}
+
+impl Error {
+    pub(crate) fn new(span: Span, message: impl Into<String>) -> Self {
+        Error {
+            messages: vec![(span, message.into())],
+        }
+    }
+
+    /// Also point out `span`, such as where something was first given.
+    pub(crate) fn note(mut self, span: Span, message: impl Into<String>) -> Self {
+        self.messages.push((span, message.into()));
+        self
+    }
+
+    /// `compile_error!` invocations reporting the error at its spans, as an expression.
+    pub(crate) fn to_compile_error(&self) -> TokenStream {
+        // A block is both a statement and an expression, so fits wherever the macro was used.
+        TokenTree::Group(Group::new(Delimiter::Brace, self.to_compile_error_items())).into()
+    }
+
+    /// `compile_error!` invocations reporting the error at its spans, as items or statements.
+    pub(crate) fn to_compile_error_items(&self) -> TokenStream {
+        let mut errors = TokenStream::new();
+        for (span, message) in &self.messages {
+            let spanned = |mut token: TokenTree| {
+                token.set_span(*span);
+                token
+            };
+            let message = spanned(TokenTree::Literal(Literal::string(message)));
+            errors.extend(vec![
+                spanned(TokenTree::Punct(Punct::new(':', Spacing::Joint))),
+                spanned(TokenTree::Punct(Punct::new(':', Spacing::Alone))),
+                spanned(TokenTree::Ident(Ident::new("core", *span))),
+                spanned(TokenTree::Punct(Punct::new(':', Spacing::Joint))),
+                spanned(TokenTree::Punct(Punct::new(':', Spacing::Alone))),
+                spanned(TokenTree::Ident(Ident::new("compile_error", *span))),
+                spanned(TokenTree::Punct(Punct::new('!', Spacing::Alone))),
+                spanned(TokenTree::Group(Group::new(
+                    Delimiter::Parenthesis,
+                    message.into(),
+                ))),
+                spanned(TokenTree::Punct(Punct::new(';', Spacing::Alone))),
+            ]);
+        }
+        errors
+    }
+}
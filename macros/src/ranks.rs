@@ -0,0 +1,549 @@
+use crate::item::LockItem;
+use crate::options::Order;
+use crate::parse::Invocation;
+use crate::quote::{ident, parenthesised, quote, separated, Error};
+use proc_macro::{Delimiter, Group, Ident, Literal, Spacing, Span, TokenStream, TokenTree};
+
+/// The hidden method giving the rank of `field`, ie `__lock_rank_connections`, or
+/// `__lock_rank_type` for `r#type`.
+fn method(field: &str, span: Span) -> Ident {
+    let field = field.strip_prefix("r#").unwrap_or(field);
+    Ident::new(&format!("__lock_rank_{}", field), span)
+}
+
+/// Implement `#[derive(LockRanks)]`, giving the struct a hidden method returning the rank of each
+/// field with a `#[lock_rank(N)]` attribute.
+///
+/// Only structs can be derived for, and the ranks must be integers:
+///
+/// ```compile_fail
+/// # use lock_order::LockRanks;
+/// #[derive(LockRanks)]
+/// enum Locks {
+///     A,
+/// }
+/// ```
+///
+/// ```compile_fail
+/// # use lock_order::LockRanks;
+/// #[derive(LockRanks)]
+/// struct Locks {
+///     #[lock_rank(first)]
+///     a: std::sync::Mutex<u32>,
+/// }
+/// ```
+pub(crate) fn derive(item: TokenStream) -> Result<TokenStream, Error> {
+    let mut tokens = item.into_iter().peekable();
+    let mut keyword = None;
+    for token in tokens.by_ref() {
+        match token {
+            TokenTree::Ident(id) if id.to_string() == "struct" => {
+                keyword = Some(id);
+                break;
+            }
+            TokenTree::Ident(id) if matches!(id.to_string().as_str(), "enum" | "union") => {
+                return Err(Error::new(
+                    id.span(),
+                    "`LockRanks` can only be derived for structs",
+                ))
+            }
+            _ => {}
+        }
+    }
+    let keyword = keyword.ok_or_else(|| {
+        Error::new(
+            Span::call_site(),
+            "`LockRanks` can only be derived for structs",
+        )
+    })?;
+    let name = match tokens.next() {
+        Some(TokenTree::Ident(name)) => name,
+        _ => {
+            return Err(Error::new(
+                keyword.span(),
+                "expected the name of the struct",
+            ))
+        }
+    };
+
+    let mut generics = Vec::new();
+    if matches!(tokens.peek(), Some(TokenTree::Punct(p)) if p.as_char() == '<') {
+        tokens.next();
+        let mut depth = 1;
+        for token in tokens.by_ref() {
+            if let TokenTree::Punct(p) = &token {
+                match p.as_char() {
+                    '<' => depth += 1,
+                    '>' => depth -= 1,
+                    _ => {}
+                }
+            }
+            if depth == 0 {
+                break;
+            }
+            generics.push(token);
+        }
+    }
+
+    let mut where_clause = TokenStream::new();
+    let mut fields = None;
+    for token in tokens {
+        match token {
+            TokenTree::Group(group)
+                if matches!(group.delimiter(), Delimiter::Brace | Delimiter::Parenthesis)
+                    && fields.is_none() =>
+            {
+                fields = Some(group);
+            }
+            TokenTree::Punct(p) if p.as_char() == ';' => break,
+            // A tuple struct's where clause comes after its fields rather than before.
+            token => where_clause.extend(Some(token)),
+        }
+    }
+    let fields = fields.ok_or_else(|| Error::new(name.span(), "expected the struct's fields"))?;
+    let named = fields.delimiter() == Delimiter::Brace;
+
+    let mut methods = TokenStream::new();
+    for (i, field) in split(fields.stream().into_iter().collect(), ',')
+        .into_iter()
+        .enumerate()
+    {
+        let rank = match rank_attribute(&field)? {
+            Some(rank) => rank,
+            None => continue,
+        };
+        let (field_name, span) = if named {
+            let id = field_name(&field).ok_or_else(|| {
+                Error::new(
+                    rank.span(),
+                    "expected a field for the `lock_rank` attribute",
+                )
+            })?;
+            (id.to_string(), id.span())
+        } else {
+            (i.to_string(), rank.span())
+        };
+        methods.extend(quote(
+            "#[doc(hidden)] #[allow(non_snake_case)] #[inline]
+            pub const fn $0(&self) -> u32 { $1 }",
+            &[
+                TokenTree::Ident(method(&field_name, span)).into(),
+                rank.into(),
+            ],
+        ));
+    }
+    if methods.is_empty() {
+        return Err(Error::new(
+            name.span(),
+            "no field has a `#[lock_rank(N)]` attribute to rank it by",
+        ));
+    }
+
+    let params = split(generics, ',');
+    let impl_generics = separated(params.iter().map(|param| {
+        // Defaults are only allowed where the parameters are declared.
+        split(param.clone(), '=')
+            .into_iter()
+            .next()
+            .unwrap_or_default()
+            .into_iter()
+            .collect()
+    }));
+    let type_generics = separated(params.iter().map(|param| generic_argument(param)));
+
+    Ok(quote(
+        "impl<$0> $1<$2> $3 { $4 }",
+        &[
+            impl_generics,
+            TokenTree::Ident(name).into(),
+            type_generics,
+            where_clause,
+            methods,
+        ],
+    ))
+}
+
+/// Split `tokens` at each `separator` outside of `<...>`, dropping any empty trailing part.
+fn split(tokens: Vec<TokenTree>, separator: char) -> Vec<Vec<TokenTree>> {
+    let mut parts = vec![Vec::new()];
+    let mut depth = 0;
+    let mut arrow = false;
+    for token in tokens {
+        if let TokenTree::Punct(p) = &token {
+            match p.as_char() {
+                // The `>` of a `->` doesn't close anything.
+                '>' if arrow => {}
+                '<' => depth += 1,
+                '>' => depth -= 1,
+                c if c == separator && depth == 0 => {
+                    parts.push(Vec::new());
+                    arrow = false;
+                    continue;
+                }
+                _ => {}
+            }
+            arrow = p.as_char() == '-' && p.spacing() == Spacing::Joint;
+        } else {
+            arrow = false;
+        }
+        parts.last_mut().unwrap().push(token);
+    }
+    if parts.last().is_some_and(Vec::is_empty) {
+        parts.pop();
+    }
+    parts
+}
+
+/// The rank given by a `#[lock_rank(N)]` attribute on `field`, if it has one.
+fn rank_attribute(field: &[TokenTree]) -> Result<Option<TokenTree>, Error> {
+    for pair in field.windows(2) {
+        let attribute = match pair {
+            [TokenTree::Punct(hash), TokenTree::Group(attribute)]
+                if hash.as_char() == '#' && attribute.delimiter() == Delimiter::Bracket =>
+            {
+                attribute
+            }
+            _ => continue,
+        };
+        let mut tokens = attribute.stream().into_iter();
+        match (tokens.next(), tokens.next()) {
+            (Some(TokenTree::Ident(name)), args) if name.to_string() == "lock_rank" => {
+                let rank = match args {
+                    Some(TokenTree::Group(args)) if args.delimiter() == Delimiter::Parenthesis => {
+                        let mut args = args.stream().into_iter();
+                        match (args.next(), args.next()) {
+                            (Some(TokenTree::Literal(rank)), None)
+                                if rank.to_string().parse::<u32>().is_ok() =>
+                            {
+                                Some(rank)
+                            }
+                            _ => None,
+                        }
+                    }
+                    _ => None,
+                };
+                return match rank {
+                    Some(rank) => Ok(Some(TokenTree::Literal(rank))),
+                    None => Err(Error::new(
+                        attribute.span(),
+                        "expected an integer rank, as in `#[lock_rank(10)]`",
+                    )),
+                };
+            }
+            _ => {}
+        }
+    }
+    Ok(None)
+}
+
+/// The name of a named field, which is the identifier before its `:`.
+fn field_name(field: &[TokenTree]) -> Option<&Ident> {
+    field.windows(2).find_map(|pair| match pair {
+        [TokenTree::Ident(name), TokenTree::Punct(colon)]
+            if colon.as_char() == ':' && colon.spacing() == Spacing::Alone =>
+        {
+            Some(name)
+        }
+        _ => None,
+    })
+}
+
+/// The argument naming a generic parameter, ie `'a` for `'a: 'b` or `N` for `const N: usize`.
+fn generic_argument(param: &[TokenTree]) -> TokenStream {
+    match param {
+        [TokenTree::Punct(quote), TokenTree::Ident(lifetime), ..] if quote.as_char() == '\'' => {
+            vec![param[0].clone(), TokenTree::Ident(lifetime.clone())]
+                .into_iter()
+                .collect()
+        }
+        [TokenTree::Ident(keyword), TokenTree::Ident(name), ..]
+            if keyword.to_string() == "const" =>
+        {
+            TokenTree::Ident(name.clone()).into()
+        }
+        [TokenTree::Ident(name), ..] => TokenTree::Ident(name.clone()).into(),
+        _ => TokenStream::new(),
+    }
+}
+
+/// The expression for the rank of `lock`, calling the method derived on the struct it's a field
+/// of.
+fn rank_of(lock: &LockItem) -> Result<TokenStream, Error> {
+    let (parent, field) = lock.field_access().ok_or_else(|| {
+        Error::new(
+            lock.span(),
+            format!(
+                "`order = rank` needs a field of a struct deriving `LockRanks`, not `{}`",
+                lock.full_identifier
+            ),
+        )
+    })?;
+    Ok(quote(
+        "$0.$1()",
+        &[
+            parent.iter().cloned().collect(),
+            TokenTree::Ident(method(&field.to_string(), field.span())).into(),
+        ],
+    ))
+}
+
+/// The statement checking that the locks of `lock_more!` rank after every lock already held, which
+/// only checks anything with the `checked` feature.
+pub(crate) fn after_held(invocation: &Invocation) -> Result<TokenStream, Error> {
+    let mut locks = Vec::new();
+    for lock in &invocation.locks {
+        let rank = if invocation.options.level.is_some() {
+            quote("$0.__rank()", &[lock.receiver()])
+        } else if let Some(rank) = lock.rank {
+            TokenTree::Literal(Literal::u64_suffixed(rank)).into()
+        } else if let Order::Rank = invocation.options.order {
+            rank_of(lock)?
+        } else {
+            return Err(Error::new(
+                lock.span(),
+                format!(
+                    "`lock_more!` needs `{}` to be ranked, with `order = hierarchy`, \
+                    `order = rank`, a lock_order.toml or a `level`",
+                    lock.full_identifier
+                ),
+            ));
+        };
+        locks.push(quote(
+            "($0, ::core::primitive::u64::from($1))",
+            &[lock.name(), rank],
+        ));
+    }
+    if !cfg!(feature = "checked") {
+        return Ok(TokenStream::new());
+    }
+    Ok(quote(
+        "::lock_order::__checked::after_held(&[$0]);",
+        &[separated(locks)],
+    ))
+}
+
+/// The locks of `invocation` in the order for ties between equal ranks, each given the expression
+/// for its rank from `rank_of`, or none for `order = address;`.
+fn sorted(
+    invocation: &Invocation,
+    rank_of: impl Fn(&LockItem) -> Result<TokenStream, Error>,
+) -> Result<Vec<LockItem>, Error> {
+    let by_address = matches!(invocation.options.order, Order::Address);
+    let order = invocation.options.order.sorted(&invocation.locks);
+    order
+        .into_iter()
+        .map(|i| {
+            let mut lock = invocation.locks[i].clone();
+            lock.rank_of = match lock.rank {
+                // A rank given inline is used as is, in whatever type the others have.
+                Some(rank) => Some(TokenTree::Literal(Literal::u64_unsuffixed(rank)).into()),
+                None if by_address => None,
+                None => Some(rank_of(&lock)?),
+            };
+            Ok(lock)
+        })
+        .collect()
+}
+
+/// The statements binding each of `locks` to a variable holding a reference to it, which the
+/// locks are then used through, so that a lock needed for its rank or address as well as to take
+/// it is only evaluated once.
+fn evaluated(locks: &mut [LockItem]) -> TokenStream {
+    let mut statements = TokenStream::new();
+    for (i, lock) in locks.iter_mut().enumerate() {
+        let local = Ident::new(&format!("__lock_order_lock_{}", i), Span::call_site());
+        statements.extend(quote("let $0 = &$1;", &[ident(&local), lock.receiver()]));
+        lock.local = Some(local);
+    }
+    statements
+}
+
+/// The expression `lock` is ordered by when the locks are taken, its rank, or its address if it
+/// doesn't have one.
+fn key(lock: &LockItem) -> TokenStream {
+    match &lock.rank_of {
+        Some(rank) => rank.clone(),
+        None => quote("::lock_order::__address(&$0)", &[lock.receiver()]),
+    }
+}
+
+/// The statements ordering `locks` from `sorted` by their ranks into `__lock_order_order`, then
+/// after `slots` running `acquire` on each lock in that order to set its slot.
+fn ranked(
+    locks: &[LockItem],
+    slots: TokenStream,
+    acquire: impl Fn(usize, &LockItem) -> TokenStream,
+) -> TokenStream {
+    let mut arms = TokenStream::new();
+    let mut ranks = Vec::new();
+    for (i, lock) in locks.iter().enumerate() {
+        ranks.push(key(lock));
+        arms.extend(quote(&format!("{} => {{ $0 }}", i), &[acquire(i, lock)]));
+    }
+    let ranks = TokenTree::Group(Group::new(Delimiter::Bracket, separated(ranks)));
+    quote(
+        "let __lock_order_order = ::lock_order::__rank_order($0);
+        $1
+        for __lock_order_i in __lock_order_order {
+            match __lock_order_i { $2 _ => ::core::unreachable!() }
+        }",
+        &[ranks.into(), slots, arms],
+    )
+}
+
+/// The most locks an order only known at runtime can take at once, as many as `lock_order` has
+/// slots for their guards.
+const MOST_TAKEN: usize = 12;
+
+/// Reject more locks than `taken` can take, which would otherwise fail on a hidden trait.
+///
+/// ```compile_fail
+/// # use lock_order::lock;
+/// # let l: Vec<_> = (0..13).map(std::sync::Mutex::new).collect();
+/// lock!(
+///     order = address;
+///     l[0] as a, l[1] as b, l[2] as c, l[3] as d, l[4] as e, l[5] as f, l[6] as g,
+///     l[7] as h, l[8] as i, l[9] as j, l[10] as k, l[11] as m, l[12] as n
+/// );
+/// ```
+fn check_count(locks: &[LockItem]) -> Result<(), Error> {
+    match locks.get(MOST_TAKEN) {
+        Some(lock) => Err(Error::new(
+            lock.span(),
+            format!(
+                "at most {} locks can be taken at once in an order only known at runtime, \
+                take the rest with another `lock!`",
+                MOST_TAKEN
+            ),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// The statements taking `locks` from `sorted` in the order of their ranks with `acquire`, and
+/// binding their guards.
+///
+/// The guards are bound as `RankedGuard`s of the slots of `__lock_order_taken`, which releases the
+/// locks in reverse of the order they were taken in, as the bindings can only be dropped in the
+/// order they're written.
+fn taken(locks: &[LockItem], acquire: impl Fn(&LockItem) -> TokenStream) -> TokenStream {
+    let slot = |i: usize| quote(&format!("&__lock_order_taken.slots.{}", i), &[]);
+    let slots = quote(
+        "let __lock_order_taken =
+            ::lock_order::__Taken::new(($0,), __lock_order_order);",
+        &[separated(
+            locks
+                .iter()
+                .map(|_| quote("::core::cell::RefCell::new(None)", &[])),
+        )],
+    );
+    let acquisitions = ranked(locks, slots, |i, lock| {
+        quote(
+            "*$0.borrow_mut() = Some($1);",
+            &[
+                quote(&format!("__lock_order_taken.slots.{}", i), &[]),
+                acquire(lock),
+            ],
+        )
+    });
+    let declarations = separated(locks.iter().map(|x| x.pattern()));
+    let guards = separated((0..locks.len()).map(|i| {
+        quote(
+            "(::lock_order::__Slot($0), ::lock_order::RankedGuard::__new($0))",
+            &[slot(i)],
+        )
+    }));
+    quote(
+        "$0 #[allow(unused_mut, unused_variables)] let $1 = $2;",
+        &[
+            acquisitions,
+            parenthesised(declarations),
+            parenthesised(guards),
+        ],
+    )
+}
+
+/// Expand `lock!(order = rank; ...)` or `lock!(order = address; ...)`, binding the guards of locks
+/// taken in the order of their ranks or addresses.
+pub(crate) fn lock_with(mut invocation: Invocation) -> Result<TokenStream, Error> {
+    check_count(&invocation.locks)?;
+    let locals = evaluated(&mut invocation.locks);
+    let backend = invocation.backend;
+    let options = &invocation.options;
+    let locks = sorted(&invocation, rank_of)?;
+
+    Ok(quote(
+        "$0 $1 $2",
+        &[
+            locals,
+            options.deadline(),
+            taken(&locks, |lock| lock.acquire_with(backend, options)),
+        ],
+    ))
+}
+
+/// Expand `try_lock!(order = rank; ...)` or `try_lock!(order = address; ...)`, trying the locks in
+/// the order of their ranks or addresses.
+pub(crate) fn try_lock_with(mut invocation: Invocation) -> Result<TokenStream, Error> {
+    let locals = evaluated(&mut invocation.locks);
+    let options = &invocation.options;
+    let order = options.order.sorted(&invocation.locks);
+    let locks = sorted(&invocation, rank_of)?;
+
+    let slots =
+        (0..locks.len()).map(|i| quote(&format!("let mut __lock_order_{} = None;", i), &[]));
+    let attempts = ranked(&locks, slots.collect(), |i, lock| {
+        quote(
+            &format!(
+                "__lock_order_{} = match $0 {{
+                    Some(guard) => Some(guard),
+                    None => break 'try_lock None,
+                }};",
+                i
+            ),
+            &[lock.try_acquire(invocation.backend, options.poison)],
+        )
+    });
+    // The guards are given in the order the locks were written.
+    let guards = separated((0..order.len()).map(|written| {
+        let slot = order.iter().position(|&i| i == written).unwrap();
+        quote(&format!("__lock_order_{}.unwrap()", slot), &[])
+    }));
+
+    Ok(quote(
+        "{ $0 'try_lock: { $1 Some($2) } }",
+        &[locals, attempts, parenthesised(guards)],
+    ))
+}
+
+/// Expand `lock!(level = token; ...)`, binding the guards of `OrderedMutex` and `OrderedRwLock`
+/// locks taken in the order of their levels, and then a new token for them in place of `token`.
+pub(crate) fn lock_at_level(
+    mut invocation: Invocation,
+    token: &Ident,
+) -> Result<TokenStream, Error> {
+    check_count(&invocation.locks)?;
+    let locals = evaluated(&mut invocation.locks);
+    let options = &invocation.options;
+    let locks = sorted(&invocation, |lock| {
+        Ok(quote("$0.__rank()", &[lock.receiver()]))
+    })?;
+
+    let levels = separated(locks.iter().map(|x| quote("$0.__level()", &[x.receiver()])));
+
+    Ok(quote(
+        // The levels are taken before the guards are bound, which may shadow the locks.
+        "$3
+        let __lock_order_held = $0.__held();
+        let __lock_order_levels = ($2,);
+        $1
+        #[allow(unused_mut, unused_variables)]
+        let mut $0 = ::lock_order::Level::__after(__lock_order_held, __lock_order_levels);",
+        &[
+            ident(token),
+            taken(&locks, |lock| lock.acquire_at_level(options.poison)),
+            levels,
+            locals,
+        ],
+    ))
+}
@@ -0,0 +1,246 @@
+use crate::item::{self, LockItem};
+use crate::options::{Backend, Order};
+use crate::parse::{parse, Invocation, Kind};
+use crate::quote::{ident, parenthesised, quote, separated, Error};
+use proc_macro::{Spacing, Span, TokenStream, TokenTree};
+
+/// What `wait_locked!` waits for, besides the locks it holds.
+#[derive(Clone)]
+pub(crate) struct Wait {
+    /// The `Condvar` to wait on.
+    condvar: TokenStream,
+    /// The `while <condition>` to keep waiting for, if there is one.
+    condition: Option<TokenStream>,
+    /// The `timeout = <Duration>;` to wait for at most, if there is one.
+    timeout: Option<TokenStream>,
+}
+
+fn is_punct(token: &TokenTree, c: char) -> bool {
+    matches!(token, TokenTree::Punct(p) if p.as_char() == c)
+}
+
+/// Split `tokens` at each `c` outside of any group.
+fn split(tokens: &[TokenTree], c: char) -> Vec<&[TokenTree]> {
+    tokens.split(|t| is_punct(t, c)).collect()
+}
+
+/// Parse the input to `wait_locked!`, `<options;> <condvar>, <lock>; <locks>; while <condition>`,
+/// with the locks parsed as if given to `lock!`, the lock to wait with first.
+pub(crate) fn parse_wait(item: TokenStream) -> Result<Invocation, Error> {
+    let tokens: Vec<TokenTree> = item.into_iter().collect();
+    if tokens.is_empty() {
+        return Err(Error::new(
+            Span::call_site(),
+            "expected a condvar and the lock to wait with",
+        ));
+    }
+    let mut locks = TokenStream::new();
+    let mut rest = tokens.as_slice();
+    // A `std:` backend, which is kept for `parse` to check.
+    if let [TokenTree::Ident(_), TokenTree::Punct(colon), ..] = rest {
+        if colon.as_char() == ':' && colon.spacing() == Spacing::Alone {
+            locks.extend(rest[..2].iter().cloned());
+            rest = &rest[2..];
+        }
+    }
+
+    let mut timeout = None;
+    let mut segments = split(rest, ';').into_iter().peekable();
+    while let Some(&[TokenTree::Ident(ref name), TokenTree::Punct(ref eq), ref value @ ..]) =
+        segments.peek().copied()
+    {
+        if eq.as_char() != '=' || eq.spacing() != Spacing::Alone {
+            break;
+        }
+        segments.next();
+        if name.to_string() == "timeout" {
+            if timeout.is_some() {
+                return Err(Error::new(
+                    name.span(),
+                    "the `timeout` option is given more than once",
+                ));
+            }
+            timeout = Some(value.iter().cloned().collect());
+        } else {
+            locks.extend(quote(
+                "$0 = $1;",
+                &[ident(name), value.iter().cloned().collect()],
+            ));
+        }
+    }
+
+    let first = segments.next().unwrap_or_default();
+    let (condvar, lock) = match first.iter().position(|t| is_punct(t, ',')) {
+        Some(comma) if comma > 0 && comma + 1 < first.len() => {
+            (&first[..comma], &first[comma + 1..])
+        }
+        _ => {
+            let span = first
+                .first()
+                .or_else(|| tokens.last())
+                .map_or_else(Span::call_site, TokenTree::span);
+            return Err(Error::new(
+                span,
+                "expected a condvar and the lock to wait with, as in `cv, mut state`",
+            ));
+        }
+    };
+    locks.extend(lock.iter().cloned());
+
+    let mut condition = None;
+    for segment in segments {
+        if condition.is_some() {
+            return Err(Error::new(
+                segment
+                    .first()
+                    .map_or_else(Span::call_site, TokenTree::span),
+                "unexpected tokens after the `while` condition",
+            ));
+        }
+        match segment {
+            [TokenTree::Ident(keyword), condition_tokens @ ..]
+                if keyword.to_string() == "while" =>
+            {
+                if condition_tokens.is_empty() {
+                    return Err(Error::new(
+                        keyword.span(),
+                        "expected a condition after `while`",
+                    ));
+                }
+                condition = Some(condition_tokens.iter().cloned().collect());
+            }
+            [] => {
+                return Err(Error::new(
+                    lock.last().map_or_else(Span::call_site, TokenTree::span),
+                    "expected the other locks held after `;`",
+                ))
+            }
+            others => {
+                locks.extend(quote(",", &[]));
+                locks.extend(others.iter().cloned());
+            }
+        }
+    }
+
+    let mut invocation = parse(locks, Backend::Std, Kind::Lock)?;
+    invocation.wait = Some(Wait {
+        condvar: condvar.iter().cloned().collect(),
+        condition,
+        timeout,
+    });
+    Ok(invocation)
+}
+
+/// Expand `wait_locked!`, given the expansion of `lock!` taking the locks again after waiting.
+pub(crate) fn expand(
+    wait: &Wait,
+    invocation: &Invocation,
+    lock: TokenStream,
+) -> Result<TokenStream, Error> {
+    if invocation.backend != Backend::Std {
+        return Err(Error::new(
+            Span::call_site(),
+            "`wait_locked!` waits on a `std::sync::Condvar`, so only takes std locks",
+        ));
+    }
+    if let Some(level) = &invocation.options.level {
+        return Err(Error::new(
+            level.span(),
+            "`wait_locked!` can't replace a `level` token, take the locks again with `lock!`",
+        ));
+    }
+    if matches!(invocation.options.order, Order::Rank | Order::Address) {
+        return Err(Error::new(
+            Span::call_site(),
+            "`wait_locked!` waits with the guard of the lock itself, so can't take locks in an \
+             order only known at runtime, use another `order`",
+        ));
+    }
+    let waiting = &invocation.locks[0];
+    // The locks are released in reverse of the order they're bound in.
+    let locks: Vec<&LockItem> = invocation
+        .options
+        .order
+        .sorted(&invocation.locks)
+        .into_iter()
+        .map(|i| &invocation.locks[i])
+        .collect();
+    let binding = |lock: &LockItem| ident(lock.binding().expect("locks are bound"));
+    let marker = |lock: &LockItem| ident(&item::marker(lock.binding().expect("locks are bound")));
+
+    let mut releases = TokenStream::new();
+    for lock in locks.iter().rev() {
+        if lock.binding_name() != waiting.binding_name() {
+            releases.extend(quote(
+                "::lock_order::__Unlock::__unlock($0, $1);",
+                &[marker(lock), binding(lock)],
+            ));
+        }
+    }
+    // The lock waited with is no longer held while waiting.
+    releases.extend(quote("::core::mem::drop($0);", &[marker(waiting)]));
+    let guard = binding(waiting);
+    let poison = invocation.options.poison;
+    let woken = match &wait.timeout {
+        None => quote(
+            "::core::mem::drop($0);",
+            &[poison.unwrap(
+                quote("$0.wait($1)", &[wait.condvar.clone(), guard]),
+                waiting,
+            )],
+        ),
+        Some(_) => quote(
+            "let (__lock_order_woken, __lock_order_waited) = $0;
+            ::core::mem::drop(__lock_order_woken);
+            if __lock_order_waited.timed_out() {
+                Err(::lock_order::TimeoutError::new($1))?
+            }",
+            &[
+                poison.unwrap(
+                    quote(
+                        "$0.wait_timeout($1,
+                            __lock_order_deadline.saturating_duration_since(
+                                ::std::time::Instant::now()))",
+                        &[wait.condvar.clone(), guard],
+                    ),
+                    waiting,
+                ),
+                waiting.name(),
+            ],
+        ),
+    };
+    let deadline = match &wait.timeout {
+        Some(timeout) => quote(
+            "let __lock_order_deadline = ::std::time::Instant::now() + ($0);",
+            std::slice::from_ref(timeout),
+        ),
+        None => TokenStream::new(),
+    };
+    // The lock waited with is taken again by the condvar, and released to be taken in order with
+    // the rest.
+    let again = quote("$0 $1 $2", &[releases, woken, lock]);
+
+    let condition = match &wait.condition {
+        Some(condition) => condition,
+        None => return Ok(quote("$0 $1", &[deadline, again])),
+    };
+    let patterns = parenthesised(separated(locks.iter().map(|lock| lock.pattern())));
+    let guards = parenthesised(separated(locks.iter().map(|lock| lock.bound())));
+    Ok(quote(
+        "$0
+        #[allow(unused_mut, unused_variables)]
+        let $1 = {
+            let mut __lock_order_guards = $2;
+            loop {
+                #[allow(unused_mut, unused_variables)]
+                let $1 = __lock_order_guards;
+                if !($3) {
+                    break $2;
+                }
+                $4
+                __lock_order_guards = $2;
+            }
+        };",
+        &[deadline, patterns, guards, condition.clone(), again],
+    ))
+}
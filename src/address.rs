@@ -0,0 +1,57 @@
+/// The address of the lock `lock` refers to, for taking `order = address;` locks lowest first.
+#[doc(hidden)]
+pub fn __address<L: Lock + ?Sized>(lock: &L) -> usize {
+    lock.__address()
+}
+
+/// The address of a lock, for telling locks apart.
+pub(crate) fn address<L: ?Sized>(lock: &L) -> usize {
+    lock as *const L as *const () as usize
+}
+
+/// A lock known by its address, found through any references or smart pointers to it so that
+/// every way of reaching it gives the same address.
+#[doc(hidden)]
+pub trait Lock {
+    fn __address(&self) -> usize;
+}
+
+macro_rules! locks {
+    ($($lock:ident)*) => {
+        $(
+            impl<T: ?Sized> Lock for std::sync::$lock<T> {
+                fn __address(&self) -> usize {
+                    address(self)
+                }
+            }
+        )*
+    };
+}
+
+locks!(Mutex RwLock);
+
+impl<T: ?Sized, const LEVEL: u32> Lock for crate::OrderedMutex<T, LEVEL> {
+    fn __address(&self) -> usize {
+        address(self)
+    }
+}
+
+impl<T: ?Sized, const LEVEL: u32> Lock for crate::OrderedRwLock<T, LEVEL> {
+    fn __address(&self) -> usize {
+        address(self)
+    }
+}
+
+macro_rules! pointers {
+    ($($pointer:ty)*) => {
+        $(
+            impl<L: Lock + ?Sized> Lock for $pointer {
+                fn __address(&self) -> usize {
+                    (**self).__address()
+                }
+            }
+        )*
+    };
+}
+
+pointers!(&L &mut L Box<L> std::rc::Rc<L> std::sync::Arc<L>);
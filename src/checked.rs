@@ -0,0 +1,382 @@
+//! The lock order checker behind the `checked` feature.
+//!
+//! Every lock taken by the macros is recorded against the locks its thread already holds, as an
+//! edge in a graph of the orders locks have been taken in. An edge which would close a cycle in
+//! the graph means two threads could each hold a lock the other is waiting for, so is reported
+//! before the lock is taken, whether or not another thread is actually waiting.
+//!
+//! As with the kernel's lockdep, the graph is of classes of locks rather than the locks
+//! themselves, as a lock can be dropped and another created at its address, which has nothing to
+//! do with the orders the first was taken in.
+
+use crate::address::{address, Lock};
+use crate::held::HeldLock;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::panic::Location;
+use std::sync::{Mutex, OnceLock, PoisonError};
+
+/// A class of locks, by the type of the lock and the field of a type it's taken as, so that
+/// `self.queue` and `other.queue` are the same class, as are the `queue`s of every instance of the
+/// struct, or otherwise by the variable it's taken through in the function it's taken in. How
+/// the lock was written is only kept for messages.
+#[doc(hidden)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Class {
+    lock: &'static str,
+    parent: &'static str,
+    field: &'static str,
+}
+
+/// The name of the type `T`, which is the same for references to it.
+fn type_name<T: ?Sized>() -> &'static str {
+    let mut name = std::any::type_name::<T>();
+    while let Some(referenced) = name.strip_prefix('&') {
+        name = referenced.strip_prefix("mut ").unwrap_or(referenced);
+    }
+    name
+}
+
+/// The type of `parent`, for the class of a lock which is a field of it, or of a function
+/// declared in the function taking a lock through a variable.
+#[doc(hidden)]
+pub fn parent<P: ?Sized>(_parent: &P) -> PhantomData<P> {
+    PhantomData
+}
+
+/// The class of `lock`, taken as the field `field` of a `P` from [`parent`], or through the
+/// variable `field` in the function `P` is declared in.
+#[doc(hidden)]
+pub fn class<L: ?Sized, P: ?Sized>(
+    _lock: &L,
+    _parent: PhantomData<P>,
+    field: &'static str,
+) -> Class {
+    Class {
+        lock: type_name::<L>(),
+        parent: type_name::<P>(),
+        field,
+    }
+}
+
+/// Where a lock was taken.
+#[derive(Clone, Copy)]
+struct Site {
+    lock: &'static str,
+    location: &'static Location<'static>,
+}
+
+impl fmt::Display for Site {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "`{}` taken at {}", self.lock, self.location)
+    }
+}
+
+/// The first time one lock was taken while holding another.
+#[derive(Clone, Copy)]
+struct Edge {
+    held: Site,
+    taken: Site,
+}
+
+/// Every order locks have been taken in so far, from each class of lock to the classes taken
+/// while holding it.
+fn graph() -> &'static Mutex<HashMap<Class, HashMap<Class, Edge>>> {
+    static GRAPH: OnceLock<Mutex<HashMap<Class, HashMap<Class, Edge>>>> = OnceLock::new();
+    GRAPH.get_or_init(Default::default)
+}
+
+/// The rank each class of lock was first given inline with `#[rank = N]`, and where.
+fn pins() -> &'static Mutex<HashMap<Class, (u64, Site)>> {
+    static PINS: OnceLock<Mutex<HashMap<Class, (u64, Site)>>> = OnceLock::new();
+    PINS.get_or_init(Default::default)
+}
+
+/// A lock held by this thread.
+struct Held {
+    id: u64,
+    /// The address of the lock, which is the lock itself for as long as it's held.
+    address: usize,
+    class: Class,
+    /// The address of what the lock's guard derefs to.
+    target: Option<usize>,
+    site: Site,
+    rank: Option<u64>,
+}
+
+thread_local! {
+    static HELD: std::cell::RefCell<Vec<Held>> = const { std::cell::RefCell::new(Vec::new()) };
+    static NEXT_ID: std::cell::Cell<u64> = const { std::cell::Cell::new(0) };
+}
+
+/// The edges leading from `from` to `to`, if `to` has ever been taken while holding `from`
+/// however indirectly.
+fn path(graph: &HashMap<Class, HashMap<Class, Edge>>, from: Class, to: Class) -> Option<Vec<Edge>> {
+    let mut seen = HashSet::new();
+    let mut stack = vec![(from, Vec::new())];
+    while let Some((key, edges)) = stack.pop() {
+        if key == to {
+            return Some(edges);
+        }
+        if !seen.insert(key) {
+            continue;
+        }
+        for (next, edge) in graph.get(&key).into_iter().flatten() {
+            let mut edges = edges.clone();
+            edges.push(*edge);
+            stack.push((*next, edges));
+        }
+    }
+    None
+}
+
+/// Record that this thread is about to take the lock at `address` of `class`, written as `lock`
+/// and ranked `rank` by the order taking it, checking it against the order of any locks already
+/// held.
+///
+/// Locks of the same class aren't ordered against each other, such as the locks `lock_all` takes,
+/// which it orders itself.
+///
+/// # Panics
+///
+/// If the lock is already held by this thread, which would wait for itself forever, or if its
+/// class has previously been held while taking the class of one of the locks already held, which
+/// means the two orders could deadlock.
+#[doc(hidden)]
+#[track_caller]
+pub fn enter(address: usize, class: Class, lock: &'static str, rank: Option<u64>) -> Entry {
+    let site = Site {
+        lock,
+        location: Location::caller(),
+    };
+    let inversion = HELD.with(|held| {
+        let held = held.borrow();
+        if let Some(earlier) = held.iter().find(|h| h.address == address) {
+            panic!(
+                "lock re-entry: {} while already holding it as {}",
+                site, earlier.site
+            );
+        }
+        let mut graph = graph().lock().unwrap_or_else(PoisonError::into_inner);
+        for earlier in held.iter() {
+            if earlier.class == class
+                || graph
+                    .get(&earlier.class)
+                    .is_some_and(|e| e.contains_key(&class))
+            {
+                continue;
+            }
+            if let Some(path) = path(&graph, class, earlier.class) {
+                return Some((earlier.site, path));
+            }
+            graph.entry(earlier.class).or_default().insert(
+                class,
+                Edge {
+                    held: earlier.site,
+                    taken: site,
+                },
+            );
+        }
+        None
+    });
+    if let Some((held, path)) = inversion {
+        let mut message = format!(
+            "lock order inversion: {} while holding {}, but previously",
+            site, held
+        );
+        for edge in path {
+            message += &format!("\n  {} while holding {}", edge.taken, edge.held);
+        }
+        panic!("{}", message);
+    }
+    push(address, class, site, rank)
+}
+
+/// Check that the lock of `class`, written as `lock` and ranked `rank` inline, was given the same
+/// rank by every call site ranking a lock of its class inline before.
+///
+/// # Panics
+///
+/// If an earlier call site gave the class a different rank, which means the two call sites don't
+/// agree on where its locks come in the order.
+#[doc(hidden)]
+#[track_caller]
+pub fn pin(class: Class, lock: &'static str, rank: u64) {
+    let site = Site {
+        lock,
+        location: Location::caller(),
+    };
+    let (pinned, earlier) = *pins()
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .entry(class)
+        .or_insert((rank, site));
+    if pinned != rank {
+        panic!(
+            "lock rank conflict: {} ranked {}, but previously {} ranked {}",
+            site, rank, earlier, pinned
+        );
+    }
+}
+
+fn push(address: usize, class: Class, site: Site, rank: Option<u64>) -> Entry {
+    let id = NEXT_ID.with(|next| {
+        let id = next.get();
+        next.set(id + 1);
+        id
+    });
+    HELD.with(|held| {
+        held.borrow_mut().push(Held {
+            id,
+            address,
+            class,
+            target: None,
+            site,
+            rank,
+        })
+    });
+    Entry { id }
+}
+
+/// The locks the current thread holds, for [`crate::held_locks`].
+pub(crate) fn held_locks() -> Vec<HeldLock> {
+    HELD.with(|held| {
+        held.borrow()
+            .iter()
+            .map(|h| HeldLock {
+                lock: h.site.lock,
+                rank: h.rank,
+                location: h.site.location,
+            })
+            .collect()
+    })
+}
+
+/// Panic unless each of `locks`, as written and with their ranks, ranks after every ranked lock
+/// this thread holds.
+#[doc(hidden)]
+#[track_caller]
+pub fn after_held(locks: &[(&'static str, u64)]) {
+    let held = held_locks();
+    let highest = match held
+        .iter()
+        .filter(|h| h.rank.is_some())
+        .max_by_key(|h| h.rank)
+    {
+        Some(highest) => highest,
+        None => return,
+    };
+    for &(lock, rank) in locks {
+        if Some(rank) <= highest.rank {
+            panic!(
+                "`{}` (rank {}) can't be taken while holding {}",
+                lock, rank, highest
+            );
+        }
+    }
+}
+
+/// Panic unless whether this thread holds the lock at `address`, or the lock whose guard derefs
+/// to `address`, written as `lock`, is `held`.
+#[doc(hidden)]
+#[track_caller]
+pub fn assert_held(address: usize, lock: &str, held: bool) {
+    let found = HELD.with(|h| {
+        h.borrow()
+            .iter()
+            .find(|h| h.address == address || h.target == Some(address))
+            .map(|h| h.site)
+    });
+    match (found, held) {
+        (Some(site), false) => panic!("`{}` is held, as {}", lock, site),
+        (None, true) => {
+            let mut message = format!("`{}` is not held", lock);
+            for lock in held_locks() {
+                message += &format!("\n  holding {}", lock);
+            }
+            panic!("{}", message);
+        }
+        _ => {}
+    }
+}
+
+/// The lock a macro is taking, whose address is found with `(&&&Probe(&lock)).__address()` and
+/// the traits below in scope. The method resolves to [`ByLock`] for a known [`Lock`], then to
+/// [`ByGuard`] for a guard, which stands for its lock by what it derefs to, or a reference or
+/// pointer to some other lock, and otherwise to [`ByPlace`], which has to take the lock as the
+/// place it's in.
+#[doc(hidden)]
+pub struct Probe<'a, L: ?Sized>(pub &'a L);
+
+#[doc(hidden)]
+pub trait ByLock {
+    fn __address(&self) -> usize;
+}
+
+impl<L: Lock + ?Sized> ByLock for &&Probe<'_, L> {
+    fn __address(&self) -> usize {
+        self.0.__address()
+    }
+}
+
+#[doc(hidden)]
+pub trait ByGuard {
+    fn __address(&self) -> usize;
+}
+
+impl<G: Deref + ?Sized> ByGuard for &Probe<'_, G> {
+    fn __address(&self) -> usize {
+        address(&**self.0)
+    }
+}
+
+#[doc(hidden)]
+pub trait ByPlace {
+    fn __address(&self) -> usize;
+}
+
+impl<L: ?Sized> ByPlace for Probe<'_, L> {
+    fn __address(&self) -> usize {
+        address(self.0)
+    }
+}
+
+/// A lock recorded as held by this thread, until this is dropped.
+#[doc(hidden)]
+pub struct Entry {
+    id: u64,
+}
+
+impl Entry {
+    /// Record that the lock's guard derefs to the address `target`.
+    pub fn guarding(self, target: usize) -> Self {
+        HELD.with(|held| {
+            if let Some(h) = held.borrow_mut().iter_mut().rfind(|h| h.id == self.id) {
+                h.target = Some(target);
+            }
+        });
+        self
+    }
+}
+
+impl<G> crate::rank::__Unlock<G> for Entry {
+    fn __unlock(self, guard: G) {
+        drop(guard);
+        drop(self);
+    }
+}
+
+impl Drop for Entry {
+    fn drop(&mut self) {
+        // Locks aren't always released in the order they were taken, such as when a guard is
+        // dropped early.
+        let _ = HELD.try_with(|held| {
+            let mut held = held.borrow_mut();
+            if let Some(i) = held.iter().rposition(|h| h.id == self.id) {
+                held.remove(i);
+            }
+        });
+    }
+}
@@ -0,0 +1,206 @@
+use crate::address::address;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Mutex, MutexGuard};
+
+/// The guards of the locks taken by [`lock_all`], [`lock_all_by_key`] or [`lock_slice`], which
+/// deref to a slice of them in the order the locks were taken.
+///
+/// The locks are released together when this is dropped, last taken first. With the `checked`
+/// feature they're recorded as held until then.
+pub struct Guards<'a, T: ?Sized> {
+    guards: Vec<MutexGuard<'a, T>>,
+    #[cfg(feature = "checked")]
+    entries: Vec<crate::checked::Entry>,
+}
+
+impl<'a, T: ?Sized> Guards<'a, T> {
+    fn with_capacity(capacity: usize) -> Self {
+        Guards {
+            guards: Vec::with_capacity(capacity),
+            #[cfg(feature = "checked")]
+            entries: Vec::with_capacity(capacity),
+        }
+    }
+
+    #[cfg(not(feature = "checked"))]
+    #[track_caller]
+    fn take(&mut self, lock: &'a Mutex<T>, _: &'static str) {
+        self.guards.push(lock.lock().unwrap());
+    }
+
+    #[cfg(feature = "checked")]
+    #[track_caller]
+    fn take(&mut self, lock: &'a Mutex<T>, name: &'static str) {
+        let class = crate::checked::class(lock, crate::checked::parent(&()), name);
+        let entry = crate::checked::enter(address(lock), class, name, None);
+        let guard = lock.lock().unwrap();
+        self.entries.push(entry.guarding(address(&*guard)));
+        self.guards.push(guard);
+    }
+
+    /// The guards themselves, which with the `checked` feature are no longer recorded as held
+    /// once this returns.
+    pub fn into_vec(mut self) -> Vec<MutexGuard<'a, T>> {
+        std::mem::take(&mut self.guards)
+    }
+}
+
+impl<T: ?Sized> Drop for Guards<'_, T> {
+    fn drop(&mut self) {
+        while let Some(guard) = self.guards.pop() {
+            drop(guard);
+            #[cfg(feature = "checked")]
+            self.entries.pop();
+        }
+    }
+}
+
+impl<'a, T: ?Sized> Deref for Guards<'a, T> {
+    type Target = [MutexGuard<'a, T>];
+
+    fn deref(&self) -> &Self::Target {
+        &self.guards
+    }
+}
+
+impl<T: ?Sized> DerefMut for Guards<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.guards
+    }
+}
+
+impl<'g, 'a, T: ?Sized> IntoIterator for &'g Guards<'a, T> {
+    type Item = &'g MutexGuard<'a, T>;
+    type IntoIter = std::slice::Iter<'g, MutexGuard<'a, T>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.guards.iter()
+    }
+}
+
+impl<'g, 'a, T: ?Sized> IntoIterator for &'g mut Guards<'a, T> {
+    type Item = &'g mut MutexGuard<'a, T>;
+    type IntoIter = std::slice::IterMut<'g, MutexGuard<'a, T>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.guards.iter_mut()
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Guards<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.guards.fmt(f)
+    }
+}
+
+/// Take each of `locks` by address, lowest first, giving their guards in that order.
+///
+/// This is for sets of locks only known at runtime, such as the shards of a map a request
+/// touches, which `lock!` can't take. Every set taken this way is taken in the same order, so two
+/// threads taking overlapping sets can't deadlock. A lock given more than once is only taken
+/// once, so there are as many guards as there are distinct locks.
+///
+/// ```
+/// use lock_order::lock_all;
+/// use std::sync::Mutex;
+///
+/// let shards = [Mutex::new(1), Mutex::new(2), Mutex::new(3)];
+/// let mut guards = lock_all(&[&shards[2], &shards[0], &shards[2]]);
+/// assert_eq!(guards.len(), 2);
+/// for guard in &mut guards {
+///     **guard += 1;
+/// }
+/// ```
+///
+/// Addresses only order the locks against each other, so any other locks held while taking them
+/// must still be taken consistently with the set as a whole.
+///
+/// # Panics
+///
+/// If one of the locks is poisoned, as `lock!` does by default.
+#[track_caller]
+pub fn lock_all<'a, T: ?Sized>(locks: &[&'a Mutex<T>]) -> Guards<'a, T> {
+    lock_all_by_key(locks, |_| ())
+}
+
+/// Take each of `locks` by the key `key` gives it, lowest first, giving their guards in that
+/// order.
+///
+/// This is [`lock_all`] for locks which already have an order of their own, such as a shard's
+/// index, so that they can be taken consistently with other code taking them in that order.
+/// Locks with the same key are taken by address, and a lock given more than once is only taken
+/// once, with `key` called once for each lock.
+///
+/// ```
+/// use lock_order::lock_all_by_key;
+/// use std::sync::Mutex;
+///
+/// let shards = [Mutex::new(1), Mutex::new(2), Mutex::new(3)];
+/// let index = |lock: &Mutex<u32>| shards.iter().position(|shard| std::ptr::eq(shard, lock));
+/// let guards = lock_all_by_key(&[&shards[2], &shards[0]], index);
+/// assert_eq!(*guards[0] + *guards[1], 4);
+/// ```
+///
+/// # Panics
+///
+/// If one of the locks is poisoned, as `lock!` does by default.
+#[track_caller]
+pub fn lock_all_by_key<'a, T: ?Sized, K: Ord>(
+    locks: &[&'a Mutex<T>],
+    mut key: impl FnMut(&'a Mutex<T>) -> K,
+) -> Guards<'a, T> {
+    let mut distinct: Vec<(usize, &'a Mutex<T>)> =
+        locks.iter().map(|&lock| (address(lock), lock)).collect();
+    distinct.sort_by_key(|&(address, _)| address);
+    distinct.dedup_by_key(|&mut (address, _)| address);
+    let mut sorted: Vec<(K, usize, &'a Mutex<T>)> = distinct
+        .into_iter()
+        .map(|(address, lock)| (key(lock), address, lock))
+        .collect();
+    sorted.sort_by(|a, b| (&a.0, a.1).cmp(&(&b.0, b.1)));
+    let mut guards = Guards::with_capacity(sorted.len());
+    for (_, _, lock) in sorted {
+        guards.take(lock, "lock_all");
+    }
+    guards
+}
+
+/// Take the locks at `indices` in `locks`, lowest index first, giving their guards in that order.
+///
+/// This is [`lock_all`] for locks kept in a slice, such as the shards of a map, where the index
+/// of a lock orders it the same as its address. An index given more than once is only taken
+/// once, so the guards are for the sorted and deduplicated `indices`.
+///
+/// ```
+/// use lock_order::lock_slice;
+/// use std::sync::Mutex;
+///
+/// let shards: Vec<_> = (0..4).map(Mutex::new).collect();
+/// let guards = lock_slice(&shards, &[3, 1, 3]);
+/// assert_eq!(guards.iter().map(|g| **g).collect::<Vec<_>>(), [1, 3]);
+/// ```
+///
+/// # Panics
+///
+/// If an index is out of bounds, before any of the locks are taken, or if one of the locks is
+/// poisoned, as `lock!` does by default.
+#[track_caller]
+pub fn lock_slice<'a, T>(locks: &'a [Mutex<T>], indices: &[usize]) -> Guards<'a, T> {
+    let mut indices = indices.to_vec();
+    indices.sort_unstable();
+    indices.dedup();
+    if let Some(&last) = indices.last() {
+        assert!(
+            last < locks.len(),
+            "lock index {} is out of bounds for {} locks",
+            last,
+            locks.len()
+        );
+    }
+    let mut guards = Guards::with_capacity(indices.len());
+    for i in indices {
+        guards.take(&locks[i], "lock_slice");
+    }
+    guards
+}
@@ -0,0 +1,108 @@
+use std::error::Error;
+use std::fmt;
+
+/// A lock could not be taken before the deadline given to `lock!(timeout = ...; ...)`.
+///
+/// Any locks already taken by the same invocation have been released again.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TimeoutError {
+    lock: &'static str,
+}
+
+impl TimeoutError {
+    #[doc(hidden)]
+    pub fn new(lock: &'static str) -> Self {
+        TimeoutError { lock }
+    }
+
+    /// The lock which timed out, as it was written in the macro invocation.
+    pub fn lock(&self) -> &'static str {
+        self.lock
+    }
+}
+
+impl fmt::Display for TimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "timed out waiting for lock `{}`", self.lock)
+    }
+}
+
+impl Error for TimeoutError {}
+
+/// A lock was poisoned by a thread panicking while holding it, returned by
+/// `lock!(poison = propagate; ...)`.
+///
+/// Any locks already taken by the same invocation have been released again.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PoisonError {
+    lock: &'static str,
+}
+
+impl PoisonError {
+    #[doc(hidden)]
+    pub fn new(lock: &'static str) -> Self {
+        PoisonError { lock }
+    }
+
+    /// The lock which was poisoned, as it was written in the macro invocation.
+    pub fn lock(&self) -> &'static str {
+        self.lock
+    }
+}
+
+impl fmt::Display for PoisonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "lock `{}` was poisoned", self.lock)
+    }
+}
+
+impl Error for PoisonError {}
+
+/// Any of the errors the macros can return, for functions which can hit more than one of them.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LockError {
+    /// See [`TimeoutError`].
+    Timeout(TimeoutError),
+    /// See [`PoisonError`].
+    Poison(PoisonError),
+}
+
+impl LockError {
+    /// The lock which couldn't be taken, as it was written in the macro invocation.
+    pub fn lock(&self) -> &'static str {
+        match self {
+            LockError::Timeout(e) => e.lock(),
+            LockError::Poison(e) => e.lock(),
+        }
+    }
+}
+
+impl From<TimeoutError> for LockError {
+    fn from(e: TimeoutError) -> Self {
+        LockError::Timeout(e)
+    }
+}
+
+impl From<PoisonError> for LockError {
+    fn from(e: PoisonError) -> Self {
+        LockError::Poison(e)
+    }
+}
+
+impl fmt::Display for LockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LockError::Timeout(e) => e.fmt(f),
+            LockError::Poison(e) => e.fmt(f),
+        }
+    }
+}
+
+impl Error for LockError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            LockError::Timeout(e) => Some(e),
+            LockError::Poison(e) => Some(e),
+        }
+    }
+}
@@ -0,0 +1,54 @@
+//! The locks the current thread holds, as far as the `checked` feature has recorded them.
+
+use std::fmt;
+use std::panic::Location;
+
+/// A lock held by the current thread, as given by [`held_locks`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct HeldLock {
+    pub(crate) lock: &'static str,
+    pub(crate) rank: Option<u64>,
+    pub(crate) location: &'static Location<'static>,
+}
+
+impl HeldLock {
+    /// The lock, as it was written in the macro invocation taking it.
+    pub fn full_identifier(&self) -> &'static str {
+        self.lock
+    }
+
+    /// The rank the lock was ordered by, if it was taken with an order that ranks locks.
+    pub fn rank(&self) -> Option<u64> {
+        self.rank
+    }
+
+    /// Where the macro taking the lock was invoked.
+    pub fn location(&self) -> &'static Location<'static> {
+        self.location
+    }
+}
+
+impl fmt::Display for HeldLock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "`{}`", self.lock)?;
+        if let Some(rank) = self.rank {
+            write!(f, " (rank {})", rank)?;
+        }
+        write!(f, " taken at {}", self.location)
+    }
+}
+
+/// The locks taken by `lock!` and `lock_all` which the current thread holds, in the order they
+/// were taken.
+///
+/// This is always empty without the `checked` feature, as the locks aren't tracked.
+pub fn held_locks() -> Vec<HeldLock> {
+    #[cfg(feature = "checked")]
+    {
+        crate::checked::held_locks()
+    }
+    #[cfg(not(feature = "checked"))]
+    {
+        Vec::new()
+    }
+}
@@ -0,0 +1,291 @@
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::{
+    LockResult, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockResult,
+};
+
+/// The level of the locks held by a [`Level`] token, which is the highest level among them.
+///
+/// This is implemented by [`At`] for a single level, by `()` for no locks at all, and by tuples of
+/// levels for a set of locks taken together.
+pub trait LockLevel {
+    const LEVEL: u32;
+}
+
+/// The level `N` of an [`OrderedMutex`] or [`OrderedRwLock`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct At<const N: u32>;
+
+impl<const N: u32> LockLevel for At<N> {
+    const LEVEL: u32 = N;
+}
+
+impl LockLevel for () {
+    const LEVEL: u32 = 0;
+}
+
+macro_rules! tuple_levels {
+    ($($name:ident)+) => {
+        impl<$($name: LockLevel),+> LockLevel for ($($name,)+) {
+            const LEVEL: u32 = {
+                let mut level = 0;
+                $(
+                    if $name::LEVEL > level {
+                        level = $name::LEVEL;
+                    }
+                )+
+                level
+            };
+        }
+    };
+}
+
+tuple_levels!(A);
+tuple_levels!(A B);
+tuple_levels!(A B C);
+tuple_levels!(A B C D);
+tuple_levels!(A B C D E);
+tuple_levels!(A B C D E F);
+tuple_levels!(A B C D E F G);
+tuple_levels!(A B C D E F G H);
+tuple_levels!(A B C D E F G H I);
+tuple_levels!(A B C D E F G H I J);
+tuple_levels!(A B C D E F G H I J K);
+tuple_levels!(A B C D E F G H I J K L);
+
+/// A token for taking [`OrderedMutex`] and [`OrderedRwLock`] locks, which only allows taking locks
+/// of a higher level than those already held.
+///
+/// A thread starts with [`Level::root`], holding nothing, and passes it down by `&mut` to wherever
+/// locks are taken. Taking a lock borrows the token for as long as the lock is held, and
+/// `lock!(level = token; ...)` gives a new token for the locks it took, so that functions called
+/// while holding them can only take higher levels still. A function taking locks while others
+/// may be held takes `&mut Level<'_, impl LockLevel>`.
+///
+/// Taking a lock of the same or a lower level than the token's is a compile error. As the levels
+/// are only known once a generic function is used, this is reported by `cargo build` rather than
+/// `cargo check`.
+pub struct Level<'a, L: LockLevel> {
+    held: PhantomData<(&'a (), L)>,
+}
+
+impl Level<'static, ()> {
+    /// The token for a thread or task which holds no locks yet.
+    ///
+    /// Nothing stops a second root token being made while the first has locks held, so this
+    /// should only be used where a thread or task starts.
+    pub fn root() -> Self {
+        Level { held: PhantomData }
+    }
+}
+
+impl<'a, L: LockLevel> Level<'a, L> {
+    /// The level of the locks held, any lock taken with this token must be higher.
+    pub fn level(&self) -> u32 {
+        L::LEVEL
+    }
+
+    #[doc(hidden)]
+    pub fn __held(&mut self) -> &Self {
+        self
+    }
+
+    #[doc(hidden)]
+    pub fn __after<'b, M: LockLevel>(_held: &'b Self, _taken: M) -> Level<'b, M> {
+        Level { held: PhantomData }
+    }
+}
+
+impl<L: LockLevel> fmt::Debug for Level<'_, L> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Level").field("level", &L::LEVEL).finish()
+    }
+}
+
+/// Fails to compile unless `LEVEL` is above the level `L` of the locks held.
+struct Above<L, const LEVEL: u32>(PhantomData<L>);
+
+impl<L: LockLevel, const LEVEL: u32> Above<L, LEVEL> {
+    const CHECK: () = assert!(
+        LEVEL > L::LEVEL,
+        "a lock can only be taken while holding locks of lower levels"
+    );
+}
+
+/// A `std::sync::Mutex` at the level `LEVEL` of the lock hierarchy, which can only be taken while
+/// holding locks of lower levels.
+///
+/// ```compile_fail
+/// use lock_order::{lock, Level, OrderedMutex};
+///
+/// let low: OrderedMutex<u32, 10> = OrderedMutex::new(1);
+/// let high: OrderedMutex<u32, 20> = OrderedMutex::new(2);
+/// let mut level = Level::root();
+/// lock!(level = level; high);
+/// // `level` is now the token for `high`, which can't take the lower `low`.
+/// let low = low.lock(&mut level).unwrap();
+/// ```
+#[derive(Default)]
+pub struct OrderedMutex<T: ?Sized, const LEVEL: u32> {
+    inner: Mutex<T>,
+}
+
+impl<T, const LEVEL: u32> OrderedMutex<T, LEVEL> {
+    pub const fn new(value: T) -> Self {
+        OrderedMutex {
+            inner: Mutex::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> LockResult<T> {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: ?Sized, const LEVEL: u32> OrderedMutex<T, LEVEL> {
+    /// Take the lock, as with `Mutex::lock`, holding `level` for as long as the guard.
+    pub fn lock<'a, L: LockLevel>(
+        &'a self,
+        level: &'a mut Level<'_, L>,
+    ) -> LockResult<MutexGuard<'a, T>> {
+        self.__lock_at(level)
+    }
+
+    /// Take the lock if it's free, as with `Mutex::try_lock`, holding `level` for as long as the
+    /// guard.
+    pub fn try_lock<'a, L: LockLevel>(
+        &'a self,
+        level: &'a mut Level<'_, L>,
+    ) -> TryLockResult<MutexGuard<'a, T>> {
+        self.__try_lock_at(level)
+    }
+
+    pub fn get_mut(&mut self) -> LockResult<&mut T> {
+        self.inner.get_mut()
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.inner.is_poisoned()
+    }
+
+    #[doc(hidden)]
+    pub fn __lock_at<'a, L: LockLevel>(
+        &'a self,
+        _held: &'a Level<'_, L>,
+    ) -> LockResult<MutexGuard<'a, T>> {
+        let () = Above::<L, LEVEL>::CHECK;
+        self.inner.lock()
+    }
+
+    #[doc(hidden)]
+    pub fn __try_lock_at<'a, L: LockLevel>(
+        &'a self,
+        _held: &'a Level<'_, L>,
+    ) -> TryLockResult<MutexGuard<'a, T>> {
+        let () = Above::<L, LEVEL>::CHECK;
+        self.inner.try_lock()
+    }
+
+    #[doc(hidden)]
+    pub fn __level(&self) -> At<LEVEL> {
+        At
+    }
+
+    #[doc(hidden)]
+    pub fn __rank(&self) -> u32 {
+        LEVEL
+    }
+}
+
+impl<T: ?Sized + fmt::Debug, const LEVEL: u32> fmt::Debug for OrderedMutex<T, LEVEL> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OrderedMutex")
+            .field("level", &LEVEL)
+            .field("inner", &&self.inner)
+            .finish()
+    }
+}
+
+/// A `std::sync::RwLock` at the level `LEVEL` of the lock hierarchy, which can only be taken while
+/// holding locks of lower levels.
+#[derive(Default)]
+pub struct OrderedRwLock<T: ?Sized, const LEVEL: u32> {
+    inner: RwLock<T>,
+}
+
+impl<T, const LEVEL: u32> OrderedRwLock<T, LEVEL> {
+    pub const fn new(value: T) -> Self {
+        OrderedRwLock {
+            inner: RwLock::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> LockResult<T> {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: ?Sized, const LEVEL: u32> OrderedRwLock<T, LEVEL> {
+    /// Take the lock for reading, as with `RwLock::read`, holding `level` for as long as the
+    /// guard.
+    pub fn read<'a, L: LockLevel>(
+        &'a self,
+        level: &'a mut Level<'_, L>,
+    ) -> LockResult<RwLockReadGuard<'a, T>> {
+        self.__read_at(level)
+    }
+
+    /// Take the lock for writing, as with `RwLock::write`, holding `level` for as long as the
+    /// guard.
+    pub fn write<'a, L: LockLevel>(
+        &'a self,
+        level: &'a mut Level<'_, L>,
+    ) -> LockResult<RwLockWriteGuard<'a, T>> {
+        self.__write_at(level)
+    }
+
+    pub fn get_mut(&mut self) -> LockResult<&mut T> {
+        self.inner.get_mut()
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.inner.is_poisoned()
+    }
+
+    #[doc(hidden)]
+    pub fn __read_at<'a, L: LockLevel>(
+        &'a self,
+        _held: &'a Level<'_, L>,
+    ) -> LockResult<RwLockReadGuard<'a, T>> {
+        let () = Above::<L, LEVEL>::CHECK;
+        self.inner.read()
+    }
+
+    #[doc(hidden)]
+    pub fn __write_at<'a, L: LockLevel>(
+        &'a self,
+        _held: &'a Level<'_, L>,
+    ) -> LockResult<RwLockWriteGuard<'a, T>> {
+        let () = Above::<L, LEVEL>::CHECK;
+        self.inner.write()
+    }
+
+    #[doc(hidden)]
+    pub fn __level(&self) -> At<LEVEL> {
+        At
+    }
+
+    #[doc(hidden)]
+    pub fn __rank(&self) -> u32 {
+        LEVEL
+    }
+}
+
+impl<T: ?Sized + fmt::Debug, const LEVEL: u32> fmt::Debug for OrderedRwLock<T, LEVEL> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OrderedRwLock")
+            .field("level", &LEVEL)
+            .field("inner", &&self.inner)
+            .finish()
+    }
+}
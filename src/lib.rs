@@ -13,9 +13,47 @@
 //!
 //! - The `mut` is optional based on if you want mutability, but must be prior to the identifier
 //! - The identifier can be multiple field lookups, ie `self.locks.connections` and will result in a
-//! bound variable `connections` as the last part of the full identifier.
+//!   bound variable `connections` as the last part of the full identifier.
+//! - Any other expression evaluating to a lock can be used too, such as `self.shard(3)` or
+//!   `self.locks[i]`, but then needs `as name` to give the bound variable a name.
 //! - There can be one or more locks provided, separated by `,`, they will be ordered
-//! lexicographially by the bound variable name.
+//!   lexicographially by the bound variable name.
+//! - A lock can be followed by `as name` to bind it to `name` instead, ie `self.a.inner as a_inner`
+//!   or `self.0 as zeroth`. The alias is the bound variable name used for ordering, unless
+//!   `order = field;` is given before the locks to order by the lock's own last identifier. Locks
+//!   which tie are ordered by the whole lock as written, so `self.a.state` comes before
+//!   `self.b.state` at every call site.
+//! - `order = path;` orders by the whole lock as written instead, and `order = natural;` by the
+//!   bound name with runs of digits compared as numbers, so `lock2` comes before `lock10`.
+//! - `order = address;` takes `std` locks lowest address first, comparing them when they're taken.
+//! - A lock can be prefixed with `read` or `write` to acquire an `RwLock` with `.read()` or
+//!   `.write()` instead of `.lock()`. These are sorted together with the plain mutexes.
+//! - The locks can be preceded by `parking_lot:` for non-poisoning locks, which are then
+//!   acquired without the `unwrap()`.
+//! - `async_lock!` does the same for `tokio::sync` locks, awaiting each lock in turn.
+//! - `try_lock!` takes the same locks without blocking, giving `None` if any of them are held.
+//! - `lock_more!` takes further locks while holding others, which must rank after those held.
+//! - `with_locks!(a, b => { ... })` holds the locks for the body alone, evaluating to its value.
+//! - `unlock!(a)` releases a guard bound by `lock!` early, before the end of the block.
+//! - `wait_locked!(cv, a; b)` waits on a `Condvar` with `a`, taking `a` and `b` again in order,
+//!   see [Condvars](#condvars).
+//! - `lock_all(&[&a, &b])` and `lock_slice(&shards, &indices)` take sets of locks only known at
+//!   runtime, see [Runtime sets of locks](#runtime-sets-of-locks).
+//! - `parking_lot` and `tokio` locks can be given a `timeout = <Duration>;` for the whole set of
+//!   locks, see [Timeouts](#timeouts).
+//! - Poisoned `std` locks can be recovered or returned as an error rather than panicking, see
+//!   [Poisoning](#poisoning).
+//! - `order = hierarchy;` orders the locks by a hierarchy declared once for the module instead, see
+//!   [Lock hierarchy](#lock-hierarchy).
+//! - A `lock_order.toml` in the crate ranks every lock in it instead, see [Manifest](#manifest).
+//! - `order = rank;` orders fields by `#[lock_rank(N)]` attributes on them, see
+//!   [Ranked fields](#ranked-fields).
+//! - `#[rank = N]` before a lock ranks it inline, taking the place of the order's own rank, see
+//!   [Inline ranks](#inline-ranks).
+//! - `level = token;` takes `OrderedMutex` and `OrderedRwLock` locks in the order of their levels,
+//!   checked at compile time, see [Lock levels](#lock-levels).
+//! - The `checked` feature checks the order of every lock taken at runtime as well, see
+//!   [Checked mode](#checked-mode).
 //!
 //! Thus an example like this:
 //! ```
@@ -49,114 +87,480 @@
 //! }
 //! ```
 //!
-
-//! ## Future direction
+//! ## RwLock
+//!
+//! ```
+//! use lock_order::lock;
+//! use std::sync::{Mutex, RwLock};
+//!
+//! let config = RwLock::new(1);
+//! let state = RwLock::new(2);
+//! let queue = Mutex::new(3);
+//! {
+//!     lock!(read config, write mut state, mut queue);
+//!     *state += *config;
+//!     *queue += *config;
+//! }
+//! ```
+//!
+//! Would expand to:
+//!
+//! ```
+//! # use std::sync::{Mutex, RwLock};
+//! # let config = RwLock::new(1);
+//! # let state = RwLock::new(2);
+//! # let queue = Mutex::new(3);
+//! let (config, mut queue, mut state) = (config.read().unwrap(), queue.lock().unwrap(),
+//! state.write().unwrap());
+//! ```
+//!
+//! ## parking_lot
+//!
+//! Locks that don't poison, such as those in `parking_lot`, return their guard directly so there
+//! is nothing to `unwrap()`. Selecting them with a `parking_lot:` prefix:
+//!
+//! ```ignore
+//! lock!(parking_lot: read config, mut queue);
+//! ```
+//!
+//! Would expand to:
+//!
+//! ```ignore
+//! let (config, mut queue) = (config.read(), queue.lock());
+//! ```
+//!
+//! ## tokio
+//!
+//! Async locks such as `tokio::sync::Mutex` and `tokio::sync::RwLock` can't be taken with
+//! `lock!`, so `async_lock!` takes the same arguments and awaits each lock in the same order:
+//!
+//! ```ignore
+//! async_lock!(read config, mut queue);
+//! ```
+//!
+//! Would expand to:
+//!
+//! ```ignore
+//! let (config, mut queue) = (config.read().await, queue.lock().await);
+//! ```
+//!
+//! ## Timeouts
+//!
+//! `parking_lot` and `tokio` locks can be given a `timeout` within which the whole set of locks
+//! must be taken. If it runs out, the locks already taken are released and a [`TimeoutError`]
+//! naming the lock that timed out is returned from the enclosing function with `?`:
+//!
+//! ```ignore
+//! fn update(&self) -> Result<(), TimeoutError> {
+//!     lock!(parking_lot: timeout = Duration::from_millis(50); mut self.queue, self.config);
+//!     // ...
+//!     Ok(())
+//! }
+//! ```
+//!
+//! For `tokio` this uses `tokio::time::timeout_at`, so needs tokio's `time` feature.
+//!
+//! ## Poisoning
+//!
+//! By default a `std` lock poisoned by a thread panicking while holding it is unwrapped, carrying
+//! the panic on into this thread. A `poison` policy can be given before the locks instead:
+//!
+//! - `poison = panic;` is the default.
+//! - `poison = recover;` takes the guard anyway with `PoisonError::into_inner`.
+//! - `poison = propagate;` releases the locks already taken and returns a [`PoisonError`] naming
+//!   the poisoned lock from the enclosing function with `?`.
+//!
+//! ```
+//! use lock_order::{lock, PoisonError};
+//! use std::sync::Mutex;
+//!
+//! fn total(queue: &Mutex<u32>, state: &Mutex<u32>) -> Result<u32, PoisonError> {
+//!     lock!(poison = propagate; queue, state);
+//!     Ok(*queue + *state)
+//! }
+//!
+//! let queue = Mutex::new(1);
+//! let state = Mutex::new(2);
+//! assert_eq!(total(&queue, &state), Ok(3));
+//! ```
+//!
+//! [`LockError`] covers both a [`PoisonError`] and a [`TimeoutError`] for functions which can hit
+//! either.
+//!
+//! ## Release order
+//!
+//! The guards of `lock!` are bound in the order their locks are taken, so they're dropped in
+//! reverse at the end of the block, releasing the last lock taken first, and `with_locks!` releases
+//! its locks the same way at the end of its body. A lock which can't be taken, because of a
+//! `timeout` or `poison = propagate;`, releases the locks already taken in reverse too.
+//!
+//! `order = rank;`, `order = address;` and `level = token;` only know the order once the locks are
+//! taken, so their guards can't be bound in it. Each is bound as a [`RankedGuard`] instead, which
+//! derefs to what the lock's own guard does, and at the end of the block they release their locks
+//! together, in reverse of the order they were taken in. `unlock!` still releases one straight
+//! away, while `wait_locked!` needs the guards themselves so can't be used with these orders. They
+//! take at most 12 locks at once.
+//!
+//! `try_lock!` gives its guards in the order they're written, and the pattern binding them decides
+//! the order they're released in.
+//!
+//! ## Condvars
+//!
+//! Waiting on a `Condvar` releases the lock it's used with while the rest stay held, and taking it
+//! again on waking takes it after them whatever the order. `wait_locked!` releases the others too,
+//! in reverse, and once woken takes them all again in order, binding the same names:
+//!
+//! ```rust
+//! use lock_order::{lock, wait_locked};
+//! use std::sync::{Condvar, Mutex};
+//!
+//! struct Shared {
+//!     queue: Mutex<Vec<u32>>,
+//!     config: Mutex<u32>,
+//!     ready: Condvar,
+//! }
+//!
+//! fn take(shared: &Shared) -> u32 {
+//!     lock!(mut shared.queue, shared.config);
+//!     wait_locked!(shared.ready, mut shared.queue; shared.config; while queue.is_empty());
+//!     queue.pop().unwrap() * *config
+//! }
+//! ```
+//!
+//! Without `while` it waits once, and with `timeout = <Duration>;` it returns a [`TimeoutError`]
+//! once the time is up. The locks are given again as they were to `lock!`, so the guards mustn't
+//! shadow them, as they're taken through fields here.
+//!
+//! ## Runtime sets of locks
+//!
+//! `lock!` takes a fixed list of locks, so can't take a set decided at runtime, such as the shards
+//! of a map a request touches. [`lock_all`] takes any number of `std` mutexes by address instead,
+//! and [`lock_slice`] takes those at some indices into a slice by index, each taking a lock given
+//! more than once only once and giving back a `Vec` of the guards in the order they were taken:
+//!
+//! ```rust
+//! use lock_order::lock_slice;
+//! use std::collections::HashMap;
+//! use std::sync::Mutex;
+//!
+//! struct Sharded {
+//!     shards: Vec<Mutex<HashMap<String, u32>>>,
+//! }
+//!
+//! impl Sharded {
+//!     fn shard(&self, key: &str) -> usize {
+//!         key.len() % self.shards.len()
+//!     }
+//!
+//!     fn total(&self, keys: &[&str]) -> u32 {
+//!         let indices: Vec<usize> = keys.iter().map(|key| self.shard(key)).collect();
+//!         let shards = lock_slice(&self.shards, &indices);
+//!         keys.iter()
+//!             .filter_map(|&key| shards.iter().find_map(|shard| shard.get(key)))
+//!             .sum()
+//!     }
+//! }
+//! ```
+//!
+//! [`lock_all_by_key`] orders the locks by a key of their own instead of by address. The guards
+//! come together in a [`Guards`], which derefs to a slice of them and releases them all when
+//! dropped.
+//!
+//! ## Lock hierarchy
+//!
+//! Sorting by name only keeps call sites consistent with each other when they lock the same
+//! names. A hierarchy of every lock can instead be declared once with [`lock_hierarchy!`], from
+//! the lock to take first to the one to take last, and then used with `order = hierarchy;`:
+//!
+//! ```
+//! use lock_order::{lock, lock_hierarchy};
+//! use std::sync::Mutex;
 //!
-//! - Support for RwLock
-//! - Support for bare non-poisoning locks such as `parking_lot`, which don't require `unwrap()`.
+//! lock_hierarchy!(registry < state < queue);
+//!
+//! let registry = Mutex::new(1);
+//! let state = Mutex::new(2);
+//! let queue = Mutex::new(3);
+//! {
+//!     lock!(order = hierarchy; mut queue, registry);
+//!     *queue += *registry;
+//! }
+//! ```
+//!
+//! Locks are ranked by the name their guard is bound to, and one missing from the hierarchy is a
+//! compile error. The hierarchy is scoped like a `macro_rules!` macro, so must be declared before
+//! it's used, either earlier in the same module or before the `mod` of a child module.
+//!
+//! ## Manifest
+//!
+//! The locks of a whole crate can be ranked in a `lock_order.toml` next to its `Cargo.toml`, so
+//! that the order comes from one reviewed file. With one there, every macro taking locks sorts
+//! them by their rank, lowest first, unless given another `order`, and a lock without a rank is a
+//! compile error:
+//!
+//! ```toml
+//! # Ranks for the whole crate.
+//! [ranks]
+//! registry = 10
+//! state = 20
+//! "self.locks.queue" = 30
+//!
+//! # Ranks only for `order = manifest(net::sessions);`, along with those in `[ranks]`.
+//! [modules."net::sessions"]
+//! sessions = 15
+//! ```
+//!
+//! A lock is looked up by the whole lock as written, such as `self.locks.queue`, and then by the
+//! name its guard is bound to. `order = manifest;` uses just the `[ranks]`, while
+//! `order = manifest(<path>);` looks in the `[modules."<path>"]` section first, so that parts of a
+//! large crate can rank their own locks.
+//!
+//! Editing the manifest rebuilds the crate, since the macros using it include it in their
+//! expansion. Adding or removing the file doesn't, as Cargo can't be told to watch for a file
+//! that isn't there, so the crate then needs a clean build, such as `cargo clean -p <crate>`, or
+//! one of its source files touching to pick up the change.
+//!
+//! ## Ranked fields
+//!
+//! The ranks can also be kept with the locks themselves, by deriving [`LockRanks`] for the struct
+//! holding them and giving each lock a `#[lock_rank(N)]`. With `order = rank;` locks which are
+//! fields of such a struct are then taken lowest rank first, and locks of equal rank by name:
+//!
+//! ```
+//! use lock_order::{lock, LockRanks};
+//! use std::sync::Mutex;
+//!
+//! #[derive(LockRanks)]
+//! struct Locks {
+//!     #[lock_rank(10)]
+//!     sessions: Mutex<u32>,
+//!     #[lock_rank(20)]
+//!     connections: Mutex<u32>,
+//! }
+//!
+//! struct Server {
+//!     locks: Locks,
+//! }
+//!
+//! impl Server {
+//!     fn connect(&self) {
+//!         lock!(order = rank; mut self.locks.connections, self.locks.sessions);
+//!         *connections += *sessions;
+//!     }
+//! }
+//! ```
+//!
+//! The ranks come from the types of the locks, which are only known after the macros have been
+//! expanded, so they're compared when the locks are taken. A lock which isn't a field of a struct
+//! deriving `LockRanks`, or a field without a rank, is still a compile error.
+//!
+//! ## Inline ranks
+//!
+//! A call site can pin the order of its locks without renaming them, by giving each a rank inline.
+//! Lower ranks are taken first, and every lock must have one unless the order already ranks them:
+//!
+//! ```rust
+//! use lock_order::lock;
+//! use std::sync::Mutex;
+//!
+//! struct Server {
+//!     registry: Mutex<u32>,
+//!     queue: Mutex<u32>,
+//! }
+//!
+//! impl Server {
+//!     fn drain(&self) {
+//!         // By name `queue` would be taken first.
+//!         lock!(#[rank = 1] self.registry, #[rank = 5] mut self.queue);
+//!         *queue += *registry;
+//!     }
+//! }
+//! ```
+//!
+//! With `order = hierarchy;`, a `lock_order.toml` or `order = rank;`, an inline rank takes the
+//! place of the rank the lock would have, which is its position in the hierarchy counting from 0,
+//! its rank in the manifest or its `#[lock_rank(N)]`, so only some of the locks need one. In
+//! [checked mode](#checked-mode), a lock ranked inline at two call sites panics the second time if
+//! they give it different ranks, as do two locks of the same [class](#checked-mode).
+//!
+//! ## Lock levels
+//!
+//! The order can also be checked by the compiler, by giving each lock a level with
+//! [`OrderedMutex`] or [`OrderedRwLock`] and taking them with a [`Level`] token. Taking a lock
+//! borrows the token for as long as it's held, and a lock can only be taken with a token for
+//! lower levels. `lock!(level = token; ...)` takes its locks in the order of their levels and
+//! then replaces `token` with a token for the levels it took, to pass on to functions called
+//! while holding them:
+//!
+//! ```
+//! use lock_order::{lock, Level, LockLevel, OrderedMutex};
+//!
+//! struct Bank {
+//!     accounts: OrderedMutex<u32, 10>,
+//!     audit: OrderedMutex<u32, 20>,
+//! }
+//!
+//! impl Bank {
+//!     fn withdraw(&self, level: &mut Level<'_, impl LockLevel>, amount: u32) {
+//!         lock!(level = level; mut self.accounts);
+//!         *accounts -= amount;
+//!         self.record(&mut level);
+//!     }
+//!
+//!     fn record(&self, level: &mut Level<'_, impl LockLevel>) {
+//!         // Taking `accounts` here instead would fail to compile.
+//!         *self.audit.lock(level).unwrap() += 1;
+//!     }
+//! }
+//!
+//! let bank = Bank {
+//!     accounts: OrderedMutex::new(100),
+//!     audit: OrderedMutex::new(0),
+//! };
+//! bank.withdraw(&mut Level::root(), 10);
+//! ```
+//!
+//! Each thread or task starts with [`Level::root`]. As the check needs the levels of any generic
+//! functions, it's reported by `cargo build` rather than `cargo check`.
+//!
+//! ## Checked mode
+//!
+//! The orders above only hold between call sites that agree on them. With the `checked` feature,
+//! meant for debug builds and tests, every lock taken by `lock!` and the macros built on it is also
+//! recorded at runtime, and taking a lock while holding another adds that order to a graph shared
+//! by every thread. An order which contradicts one taken before, however indirectly, panics before
+//! the lock is taken, naming both locks and where each order was taken, even if no thread ever
+//! deadlocked. Taking a lock the thread already holds panics too, naming where it's held, instead
+//! of waiting for itself forever:
+//!
+//! ```toml
+//! [dev-dependencies]
+//! lock_order = { version = "0.1", features = ["checked"] }
+//! ```
+//!
+//! Orders are kept between classes of locks rather than the locks themselves, as the kernel's
+//! lockdep does, since a lock can be dropped and another created at its address. A lock taken as a
+//! field is of the class of that field of its type, so `self.queue` in one function and
+//! `other.queue` in another are the same class, as are the `queue`s of every instance of the
+//! struct, and taking two fields of a type in one order and then the other panics even if it's on
+//! different instances. Any other lock is of the class of the variable or expression it's taken
+//! through, in the function it's taken in. Locks of the same class aren't ordered against each
+//! other, and neither are the locks `lock_all` and friends take, which order them themselves.
+//!
+//! Taking a lock the thread already holds is found by the lock's address, which can't be reused
+//! while the lock is held. `std` locks, `OrderedMutex` and `OrderedRwLock` are followed through
+//! references, `Box`, `Rc` and `Arc` to the lock itself, so the same lock is recognised however
+//! it's reached. Other locks are followed through a reference or smart pointer to what it points
+//! to.
+//!
+//! The guards keep their own types, so turning the feature on changes nothing else. A lock is
+//! recorded as held alongside its guard, until the end of the scope the guard is bound in or until
+//! `unlock!` releases it, so a guard moved elsewhere still counts as held until then. The locks
+//! taken by `lock_all` and friends are held until their `Guards` is dropped. `try_lock!` hands its
+//! guards back rather than binding them, so its locks aren't recorded, and neither are those of
+//! `async_lock!`, as a task can move between threads while holding them.
+//!
+//! The locks the current thread holds are given by `held_locks()`, with the lock as written, its
+//! rank for the orders that rank locks, and where it was taken. `assert_holds!` and
+//! `assert_not_held!` check them for functions which expect their callers to hold a lock, or not
+//! to. Without the `checked` feature nothing is tracked, so `held_locks()` is always empty and the
+//! assertions do nothing:
+//!
+//! ```ignore
+//! fn apply(&self, state: &mut State) {
+//!     assert_holds!(self.state);
+//!     // ...
+//! }
+//! ```
 
-use proc_macro::{self, TokenStream};
+mod address;
+#[cfg(feature = "checked")]
+mod checked;
+mod dynamic;
+mod error;
+mod held;
+mod level;
+mod rank;
 
-#[derive(Clone, PartialEq, Debug, Default)]
-struct LockItem {
-    last_identifier: String,
-    full_identifier: String,
-    mutable: bool,
-}
+pub use dynamic::{lock_all, lock_all_by_key, lock_slice, Guards};
+pub use error::{LockError, PoisonError, TimeoutError};
+pub use held::{held_locks, HeldLock};
+pub use level::{At, Level, LockLevel, OrderedMutex, OrderedRwLock};
+pub use lock_order_macros::{
+    async_lock, lock, lock_hierarchy, lock_more, try_lock, unlock, wait_locked, with_locks,
+    LockRanks,
+};
+pub use rank::RankedGuard;
+
+#[doc(hidden)]
+pub use address::__address;
+#[doc(hidden)]
+pub use rank::{__Slot, __Slots, __Taken, __Unlock, __rank_order};
 
-impl LockItem {
-    fn add(&mut self, id: &proc_macro::TokenTree) {
-        self.full_identifier += &id.to_string();
-        self.last_identifier = id.to_string();
-    }
+#[cfg(feature = "checked")]
+#[doc(hidden)]
+pub mod __checked {
+    pub use crate::address::Lock;
+    pub use crate::checked::{
+        after_held, assert_held, class, enter, parent, pin, ByGuard, ByLock, ByPlace, Class, Probe,
+    };
 }
 
-/// Lock one or more locks at a time.
-///
-/// This takes multiple lock arguments (with an optional `mut` flag) and creates a single let
-/// expression binding the `.lock().unwrap()` into variables of the same name as the last identifier
-/// in the lock expression. This means that if something is passed such as:
-///
-/// ```
-/// # use lock_order::lock;
-/// # use std::sync::Mutex;
-/// # struct Inner {
-/// #    connections: Mutex<u32>,
-/// # }
-/// # struct Test {
-/// #    locks: Inner,
-/// # }
-/// # impl Test {
-/// # fn test(&self) {
-/// lock!(mut self.locks.connections);
-/// # }
-/// # }
-/// ```
+#[doc(hidden)]
+pub use lock_order_macros::__lock_ranked;
+
+/// Assert that the current thread holds each of the locks, as taken by `lock!` or `lock_all`, for
+/// functions which expect their callers to have taken them. A lock can also be given by the guard
+/// `lock!` bound for it.
 ///
-/// Then the output will be something similar to:
+/// This only checks anything with the `checked` feature, otherwise the locks aren't tracked and
+/// it does nothing, as with `debug_assert!`.
 ///
+/// ```ignore
+/// fn apply(&self, state: &mut State) {
+///     assert_holds!(self.state);
+///     // ...
+/// }
 /// ```
-/// # use lock_order::lock;
-/// # use std::sync::Mutex;
-/// # struct Inner {
-/// #    connections: Mutex<u32>,
-/// # }
-/// # struct Test {
-/// #     locks: Inner,
-/// # }
-/// # impl Test {
-/// # fn test(&self) {
-/// let (mut connection) = (self.locks.connections.lock().unwrap());
-/// # }
-/// # }
-/// ```
-#[proc_macro]
-pub fn lock(item: TokenStream) -> TokenStream {
-    let mut out = Vec::new();
-    let mut curr = LockItem::default();
-    for i in item {
-        // FIX this should probably not be just operating on strings
-        match i.to_string().as_str() {
-            "mut" => {
-                curr.mutable = true;
-            }
-            "," => {
-                out.push(curr);
-                curr = LockItem::default();
-            }
-            _ => {
-                curr.add(&i);
-            }
-        }
-    }
-
-    if curr != LockItem::default() {
-        out.push(curr);
-    }
+#[macro_export]
+macro_rules! assert_holds {
+    ($($lock:expr),+ $(,)?) => {
+        $($crate::__assert_held!($lock, true);)+
+    };
+}
 
-    out.sort_by(|a, b| a.last_identifier.partial_cmp(&b.last_identifier).unwrap());
+/// Assert that the current thread holds none of the locks, as taken by `lock!` or `lock_all`, for
+/// functions which take them themselves.
+///
+/// As with [`assert_holds!`], this only checks anything with the `checked` feature.
+#[macro_export]
+macro_rules! assert_not_held {
+    ($($lock:expr),+ $(,)?) => {
+        $($crate::__assert_held!($lock, false);)+
+    };
+}
 
-    let declarations: Vec<String> = out
-        .clone()
-        .into_iter()
-        .map(|x| {
-            if x.mutable {
-                format!("mut {}", x.last_identifier)
-            } else {
-                x.last_identifier.clone()
-            }
-        })
-        .collect();
-    let locks: Vec<String> = out
-        .into_iter()
-        .map(|x| format!("{}.lock().unwrap()", x.full_identifier))
-        .collect();
+#[cfg(feature = "checked")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __assert_held {
+    ($lock:expr, $held:expr) => {{
+        use $crate::__checked::{ByGuard as _, ByLock as _, ByPlace as _};
+        $crate::__checked::assert_held(
+            (&&&$crate::__checked::Probe(&$lock)).__address(),
+            ::core::stringify!($lock),
+            $held,
+        );
+    }};
+}
 
-    format!(
-        "let ({}) = ({});",
-        declarations.join(", "),
-        locks.join(", "),
-    )
-    .parse()
-    .unwrap()
+#[cfg(not(feature = "checked"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __assert_held {
+    ($lock:expr, $held:expr) => {
+        if false {
+            let _ = &$lock;
+        }
+    };
 }
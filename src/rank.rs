@@ -0,0 +1,155 @@
+use std::cell::{RefCell, RefMut};
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+
+/// The order to take locks with `ranks` in, as positions in `ranks`.
+///
+/// Lower ranks, or addresses for `order = address;`, are taken first, and locks with the same rank
+/// are taken in the order they're given in.
+#[doc(hidden)]
+pub fn __rank_order<K: Ord, const N: usize>(ranks: [K; N]) -> [usize; N] {
+    let mut order = [0; N];
+    for (i, position) in order.iter_mut().enumerate() {
+        *position = i;
+    }
+    order.sort_by(|&a, &b| ranks[a].cmp(&ranks[b]));
+    order
+}
+
+/// The guard bound by `lock!` for a lock taken in an order only known at runtime, with
+/// `order = rank;`, `order = address;` or a `level = token;`, which derefs to what the lock's own
+/// guard does.
+///
+/// The guards of the locks a macro takes this way are kept together, so that when they go out of
+/// scope the locks are released in reverse of the order they were taken in. `unlock!` still
+/// releases one early.
+pub struct RankedGuard<'a, G>(RefMut<'a, G>);
+
+impl<'a, G> RankedGuard<'a, G> {
+    #[doc(hidden)]
+    pub fn __new<M>(slot: &'a RefCell<Option<(M, G)>>) -> Self {
+        RankedGuard(RefMut::map(slot.borrow_mut(), |slot| {
+            &mut slot.as_mut().expect("the lock has been taken").1
+        }))
+    }
+}
+
+impl<G: Deref> Deref for RankedGuard<'_, G> {
+    type Target = G::Target;
+
+    fn deref(&self) -> &G::Target {
+        &self.0
+    }
+}
+
+impl<G: DerefMut> DerefMut for RankedGuard<'_, G> {
+    fn deref_mut(&mut self) -> &mut G::Target {
+        &mut self.0
+    }
+}
+
+impl<G: fmt::Debug> fmt::Debug for RankedGuard<'_, G> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// The locks taken by one macro in an order only known at runtime, which are released in reverse
+/// of that order when this is dropped, whether the macro took them all or gave up part way.
+///
+/// `slots` is a tuple with a `RefCell<Option<(marker, guard)>>` for each lock, in the order the
+/// locks are written, and `order` the positions in it in the order the locks are taken.
+#[doc(hidden)]
+pub struct __Taken<S: __Slots, const N: usize> {
+    pub slots: S,
+    order: [usize; N],
+}
+
+impl<S: __Slots, const N: usize> __Taken<S, N> {
+    pub fn new(slots: S, order: [usize; N]) -> Self {
+        __Taken { slots, order }
+    }
+}
+
+impl<S: __Slots, const N: usize> Drop for __Taken<S, N> {
+    fn drop(&mut self) {
+        for &i in self.order.iter().rev() {
+            self.slots.__release(i);
+        }
+    }
+}
+
+#[doc(hidden)]
+pub trait __Slots {
+    /// Release the lock in slot `i`, if it's held.
+    fn __release(&self, i: usize);
+}
+
+fn release<M, G>(slot: &RefCell<Option<(M, G)>>) {
+    if let Some((marker, guard)) = slot.borrow_mut().take() {
+        drop(guard);
+        drop(marker);
+    }
+}
+
+/// A slot of [`__Slots`], holding a lock's guard and marker while it's held.
+pub trait Slot {
+    fn release(&self);
+}
+
+impl<M, G> Slot for RefCell<Option<(M, G)>> {
+    fn release(&self) {
+        release(self);
+    }
+}
+
+macro_rules! tuple_slots {
+    ($($i:tt $name:ident)+) => {
+        impl<$($name: Slot),+> __Slots for ($($name,)+) {
+            fn __release(&self, i: usize) {
+                match i {
+                    $($i => self.$i.release(),)+
+                    _ => {}
+                }
+            }
+        }
+    };
+}
+
+// As many as `lock_order_macros` takes in an order only known at runtime.
+tuple_slots!(0 A);
+tuple_slots!(0 A 1 B);
+tuple_slots!(0 A 1 B 2 C);
+tuple_slots!(0 A 1 B 2 C 3 D);
+tuple_slots!(0 A 1 B 2 C 3 D 4 E);
+tuple_slots!(0 A 1 B 2 C 3 D 4 E 5 F);
+tuple_slots!(0 A 1 B 2 C 3 D 4 E 5 F 6 G);
+tuple_slots!(0 A 1 B 2 C 3 D 4 E 5 F 6 G 7 H);
+tuple_slots!(0 A 1 B 2 C 3 D 4 E 5 F 6 G 7 H 8 I);
+tuple_slots!(0 A 1 B 2 C 3 D 4 E 5 F 6 G 7 H 8 I 9 J);
+tuple_slots!(0 A 1 B 2 C 3 D 4 E 5 F 6 G 7 H 8 I 9 J 10 K);
+tuple_slots!(0 A 1 B 2 C 3 D 4 E 5 F 6 G 7 H 8 I 9 J 10 K 11 L);
+
+/// The marker bound alongside a [`RankedGuard`], which `unlock!` gives the guard to so that the
+/// lock is released straight away rather than along with the rest.
+#[doc(hidden)]
+pub struct __Slot<'a, M, G>(pub &'a RefCell<Option<(M, G)>>);
+
+/// What's released along with a guard by `unlock!`, given the marker bound alongside it.
+#[doc(hidden)]
+pub trait __Unlock<G> {
+    fn __unlock(self, guard: G);
+}
+
+impl<G> __Unlock<G> for () {
+    fn __unlock(self, guard: G) {
+        drop(guard);
+    }
+}
+
+impl<M, G, H> __Unlock<H> for __Slot<'_, M, G> {
+    fn __unlock(self, guard: H) {
+        drop(guard);
+        release(self.0);
+    }
+}
@@ -1,5 +1,5 @@
-use lock_order::lock;
-use std::sync::Mutex;
+use lock_order::{lock, unlock};
+use std::sync::{Mutex, RwLock};
 
 #[test]
 fn simple_usage() {
@@ -14,3 +14,126 @@ fn simple_usage() {
         lock!(mut lock2);
     }
 }
+
+#[test]
+fn rwlock_usage() {
+    let config = RwLock::new(1);
+    let state = RwLock::new(2);
+    let queue = Mutex::new(3);
+    {
+        lock!(read config, write mut state, mut queue);
+        *state += *config;
+        *queue += *config;
+    }
+    {
+        lock!(read config, read state);
+        assert_eq!(*config, 1);
+        assert_eq!(*state, 3);
+    }
+    assert_eq!(*queue.lock().unwrap(), 4);
+}
+
+#[test]
+fn mode_names_as_locks() {
+    let read = Mutex::new(1);
+    let write = Mutex::new(2);
+    {
+        lock!(mut read, write);
+        *read += *write;
+    }
+    assert_eq!(*read.lock().unwrap(), 3);
+    {
+        lock!(mut read as r, write);
+        *r += *write;
+    }
+    {
+        lock!(read, mut write as w);
+        *w += *read;
+    }
+    assert_eq!(*write.lock().unwrap(), 7);
+}
+
+struct Pair(Mutex<u32>, Mutex<u32>);
+
+struct Nested {
+    a: Pair,
+    b: Pair,
+}
+
+impl Nested {
+    fn swap(&self) {
+        lock!(mut self.a.0 as a_zeroth, mut self.b.0 as b_zeroth, self.a.1 as first);
+        std::mem::swap(&mut *a_zeroth, &mut *b_zeroth);
+        *a_zeroth += *first;
+    }
+}
+
+#[test]
+fn aliases() {
+    let nested = Nested {
+        a: Pair(Mutex::new(1), Mutex::new(2)),
+        b: Pair(Mutex::new(3), Mutex::new(4)),
+    };
+    nested.swap();
+    assert_eq!(*nested.a.0.lock().unwrap(), 5);
+    assert_eq!(*nested.b.0.lock().unwrap(), 1);
+
+    let lock1 = Mutex::new(1);
+    let lock2 = Mutex::new(2);
+    {
+        lock!(order = field; mut lock1 as second, mut lock2 as first);
+        *first += *second;
+    }
+    assert_eq!(*lock2.lock().unwrap(), 3);
+}
+
+struct Locks {
+    queue: Mutex<u32>,
+    config: RwLock<u32>,
+    state: Mutex<u32>,
+}
+
+#[test]
+fn early_unlock() {
+    let locks = Locks {
+        queue: Mutex::new(1),
+        config: RwLock::new(2),
+        state: Mutex::new(3),
+    };
+    lock!(mut locks.queue, read locks.config, locks.state);
+    *queue += *config;
+    unlock!(config, state);
+    assert!(locks.config.try_write().is_ok());
+    assert!(locks.state.try_lock().is_ok());
+    *queue += 1;
+    unlock!(queue);
+    assert_eq!(*locks.queue.lock().unwrap(), 4);
+}
+
+struct Token {
+    r#type: Mutex<u32>,
+    r#ref: Mutex<u32>,
+}
+
+#[test]
+fn raw_identifiers() {
+    let token = Token {
+        r#type: Mutex::new(1),
+        r#ref: Mutex::new(2),
+    };
+    lock!(mut token.r#type, token.r#ref);
+    *r#type += *r#ref;
+    unlock!(r#ref);
+    assert!(token.r#ref.try_lock().is_ok());
+    assert_eq!(*r#type, 3);
+}
+
+#[cfg(not(feature = "checked"))]
+#[test]
+fn untracked() {
+    let lock = Mutex::new(1);
+    lock!(lock);
+    lock_order::assert_holds!(lock);
+    assert!(lock_order::held_locks().is_empty());
+    assert_eq!(*lock, 1);
+}
@@ -0,0 +1,359 @@
+#![cfg(feature = "checked")]
+
+use lock_order::{
+    assert_holds, assert_not_held, held_locks, lock, lock_all, lock_hierarchy, lock_more,
+    lock_slice, try_lock, unlock, wait_locked, TimeoutError,
+};
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::Duration;
+
+lock_hierarchy!(registry < state);
+
+fn add(total: &Mutex<u32>, amount: &Mutex<u32>) {
+    lock!(mut total, amount);
+    *total += *amount;
+}
+
+#[test]
+fn consistent_order() {
+    let total = Mutex::new(0);
+    let amount = Mutex::new(2);
+    add(&total, &amount);
+    add(&total, &amount);
+    // Taking one lock at a time is in no order at all.
+    {
+        lock!(amount);
+        assert_eq!(*amount, 2);
+    }
+    lock!(total);
+    assert_eq!(*total, 4);
+}
+
+#[test]
+#[should_panic(expected = "lock order inversion: `first` taken at")]
+fn inversion() {
+    let first = Mutex::new(1);
+    let second = Mutex::new(2);
+    {
+        lock!(first, second);
+        assert_eq!(*first + *second, 3);
+    }
+    // Each is taken alone, so the order only shows across the two calls.
+    lock!(second as a);
+    lock!(first as b);
+    assert_eq!(*a + *b, 3);
+}
+
+struct Pair {
+    left: Mutex<u32>,
+    right: Mutex<u32>,
+}
+
+fn left_then_right(pair: &Pair) -> u32 {
+    lock!(pair.left);
+    lock!(pair.right);
+    *left + *right
+}
+
+fn right_then_left(other: &Pair) -> u32 {
+    lock!(other.right);
+    lock!(other.left);
+    *left + *right
+}
+
+#[test]
+#[should_panic(expected = "lock order inversion: `other.left` taken at")]
+fn inversion_through_other_names() {
+    let pair = Pair {
+        left: Mutex::new(1),
+        right: Mutex::new(2),
+    };
+    assert_eq!(left_then_right(&pair), 3);
+    // The same locks, written through another binding.
+    right_then_left(&pair);
+}
+
+struct Connection {
+    socket: Mutex<u32>,
+    buffer: Mutex<u32>,
+}
+
+struct Session {
+    socket: Mutex<u32>,
+    buffer: Mutex<u32>,
+}
+
+#[test]
+fn reallocated_locks() {
+    {
+        let connection = Box::new(Connection {
+            socket: Mutex::new(1),
+            buffer: Mutex::new(2),
+        });
+        lock!(connection.socket);
+        lock!(connection.buffer);
+        assert_eq!(*socket + *buffer, 3);
+    }
+    // Likely allocated where the connection was, but its locks are of other classes.
+    let session = Box::new(Session {
+        socket: Mutex::new(1),
+        buffer: Mutex::new(2),
+    });
+    lock!(session.buffer);
+    lock!(session.socket);
+    assert_eq!(*socket + *buffer, 3);
+}
+
+#[test]
+#[should_panic(expected = "lock re-entry: `total` taken at")]
+fn reentry() {
+    let total = Mutex::new(0);
+    let alias = &total;
+    lock!(alias);
+    add(&total, &Mutex::new(1));
+    assert_eq!(*alias, 1);
+}
+
+#[test]
+fn try_lock_is_unchecked() {
+    let first = Mutex::new(1);
+    let second = Mutex::new(2);
+    {
+        lock!(first, second);
+    }
+    lock!(second);
+    let first = try_lock!(first).expect("`first` is free");
+    assert_eq!(*first + *second, 3);
+}
+
+#[test]
+fn guards_keep_their_type() {
+    fn read(guard: MutexGuard<'_, u32>) -> u32 {
+        *guard
+    }
+
+    let queue = Mutex::new(3);
+    lock!(queue);
+    assert_eq!(read(queue), 3);
+}
+
+struct Server {
+    registry: Mutex<u32>,
+    state: Mutex<u32>,
+}
+
+impl Server {
+    fn register(&self) {
+        assert_not_held!(self.registry);
+        lock!(order = hierarchy; mut self.state, self.registry);
+        self.apply(&mut state);
+        assert_holds!(state, registry);
+    }
+
+    fn apply(&self, state: &mut u32) {
+        assert_holds!(self.state);
+        *state += 1;
+    }
+}
+
+#[test]
+fn held() {
+    let server = Server {
+        registry: Mutex::new(0),
+        state: Mutex::new(0),
+    };
+    assert!(held_locks().is_empty());
+    server.register();
+    assert!(held_locks().is_empty());
+
+    lock!(order = hierarchy; server.state, server.registry);
+    let held = held_locks();
+    let locks: Vec<_> = held
+        .iter()
+        .map(|h| (h.full_identifier(), h.rank()))
+        .collect();
+    assert_eq!(
+        locks,
+        [("server.registry", Some(0)), ("server.state", Some(1))]
+    );
+    assert_eq!(held[0].location().file(), file!());
+}
+
+#[test]
+#[should_panic(expected = "`self.state` is not held")]
+fn not_held() {
+    let server = Server {
+        registry: Mutex::new(0),
+        state: Mutex::new(0),
+    };
+    server.apply(&mut 0);
+}
+
+#[test]
+fn more() {
+    let registry = Mutex::new(1);
+    let state = Mutex::new(2);
+    lock!(order = hierarchy; registry);
+    lock_more!(order = hierarchy; mut state);
+    *state += *registry;
+    assert_eq!(held_locks().len(), 2);
+}
+
+#[test]
+#[should_panic(expected = "`registry` (rank 0) can't be taken while holding `state` (rank 1)")]
+fn more_out_of_order() {
+    let registry = Mutex::new(1);
+    let state = Mutex::new(2);
+    lock!(order = hierarchy; state);
+    lock_more!(order = hierarchy; registry);
+    assert_eq!(*state + *registry, 3);
+}
+
+#[test]
+fn unlocked() {
+    let registry = Mutex::new(1);
+    let state = Mutex::new(2);
+    lock!(order = hierarchy; registry, state);
+    unlock!(registry);
+    let held: Vec<_> = held_locks().iter().map(|h| h.full_identifier()).collect();
+    assert_eq!(held, ["state"]);
+    assert_eq!(*state, 2);
+}
+
+fn wait_at_once(locks: &(Mutex<u32>, Mutex<u32>), ready: &Condvar) -> Result<(), TimeoutError> {
+    lock!(order = hierarchy; locks.0 as registry, locks.1 as state);
+    wait_locked!(
+        order = hierarchy; timeout = Duration::ZERO;
+        ready, locks.1 as state; locks.0 as registry
+    );
+    assert_eq!(*registry + *state, 3);
+    Ok(())
+}
+
+#[test]
+fn waited() {
+    let locks = (Mutex::new(1), Mutex::new(2));
+    let ready = Condvar::new();
+    assert!(wait_at_once(&locks, &ready).is_err());
+    assert!(held_locks().is_empty());
+
+    lock!(order = hierarchy; locks.0 as registry, mut locks.1 as state);
+    wait_locked!(
+        order = hierarchy;
+        ready, mut locks.1 as state; locks.0 as registry; while *state < 2
+    );
+    *state += *registry;
+    let held: Vec<_> = held_locks().iter().map(|h| h.full_identifier()).collect();
+    assert_eq!(held, ["locks.0", "locks.1"]);
+}
+
+#[test]
+#[should_panic(expected = "lock re-entry: `lock_slice` taken at")]
+fn slice_reentry() {
+    let shards = [Mutex::new(0), Mutex::new(1)];
+    lock!(shards[1] as shard);
+    let guards = lock_slice(&shards, &[0, 1]);
+    assert_eq!(*shard, guards.len());
+}
+
+#[test]
+#[should_panic(expected = "lock order inversion: `lock_all` taken at")]
+fn all_against_lock() {
+    let shards = [Mutex::new(0), Mutex::new(1)];
+    {
+        let first = lock_all(&[&shards[0]]);
+        lock!(shards[1] as second);
+        assert_eq!(*first[0], *second - 1);
+    }
+    // The same two locks, taken the other way around.
+    lock!(shards[1] as second);
+    let first = lock_all(&[&shards[0]]);
+    assert_eq!(*first[0], *second - 1);
+}
+
+fn pinned(locks: &(Mutex<u32>, Mutex<u32>), rank: u64) {
+    match rank {
+        1 => {
+            lock!(
+                #[rank = 1]
+                locks.0 as first,
+                #[rank = 2]
+                locks.1 as second
+            );
+            assert_eq!(*first + *second, 3);
+        }
+        _ => {
+            lock!(
+                #[rank = 3]
+                locks.0 as first,
+                #[rank = 2]
+                locks.1 as second
+            );
+            assert_eq!(*first + *second, 3);
+        }
+    }
+}
+
+#[test]
+#[should_panic(expected = "lock rank conflict: `locks.0` taken at")]
+fn rank_conflict() {
+    let locks = (Mutex::new(1), Mutex::new(2));
+    pinned(&locks, 1);
+    pinned(&locks, 1);
+    pinned(&locks, 3);
+}
+
+#[test]
+#[should_panic(expected = "lock rank conflict: `other.left` taken at")]
+fn rank_conflict_through_other_names() {
+    let pair = Pair {
+        left: Mutex::new(1),
+        right: Mutex::new(2),
+    };
+    {
+        lock!(
+            #[rank = 1]
+            pair.left,
+            #[rank = 2]
+            pair.right
+        );
+        assert_eq!(*left + *right, 3);
+    }
+    // The same locks, written through another binding.
+    let other = &pair;
+    lock!(
+        #[rank = 3]
+        other.left,
+        #[rank = 2]
+        other.right
+    );
+}
+
+#[test]
+fn reallocated_pins() {
+    {
+        let connection = Box::new(Connection {
+            socket: Mutex::new(1),
+            buffer: Mutex::new(2),
+        });
+        lock!(
+            #[rank = 1]
+            connection.socket,
+            #[rank = 2]
+            connection.buffer
+        );
+        assert_eq!(*socket + *buffer, 3);
+    }
+    // Likely allocated where the connection was, but its locks are of other classes.
+    let session = Box::new(Session {
+        socket: Mutex::new(1),
+        buffer: Mutex::new(2),
+    });
+    lock!(
+        #[rank = 2]
+        session.socket,
+        #[rank = 1]
+        session.buffer
+    );
+    assert_eq!(*socket + *buffer, 3);
+}
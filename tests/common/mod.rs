@@ -0,0 +1,54 @@
+//! Locks for the tests which record what's done with them, standing in for real locks.
+
+// Each test crate only uses some of these.
+#![allow(dead_code)]
+
+use std::cell::RefCell;
+use std::convert::Infallible;
+use std::sync::TryLockError;
+
+/// A lock which records when it's taken.
+pub struct Recorded<'a> {
+    pub name: &'static str,
+    pub log: &'a RefCell<Vec<&'static str>>,
+}
+
+impl Recorded<'_> {
+    pub fn lock(&self) -> Result<&'static str, Infallible> {
+        self.log.borrow_mut().push(self.name);
+        Ok(self.name)
+    }
+
+    pub fn try_lock(&self) -> Result<&'static str, TryLockError<()>> {
+        self.log.borrow_mut().push(self.name);
+        Ok(self.name)
+    }
+}
+
+/// A lock which records when it's taken and released, or fails to be taken if `poisoned`.
+pub struct Released<'a> {
+    pub name: &'static str,
+    pub log: &'a RefCell<Vec<String>>,
+    pub poisoned: bool,
+}
+
+pub struct Guard<'a>(&'a Released<'a>);
+
+impl Drop for Guard<'_> {
+    fn drop(&mut self) {
+        self.0
+            .log
+            .borrow_mut()
+            .push(format!("release {}", self.0.name));
+    }
+}
+
+impl<'a> Released<'a> {
+    pub fn lock(&'a self) -> Result<Guard<'a>, ()> {
+        if self.poisoned {
+            return Err(());
+        }
+        self.log.borrow_mut().push(format!("take {}", self.name));
+        Ok(Guard(self))
+    }
+}
@@ -0,0 +1,78 @@
+use lock_order::{lock_all, lock_all_by_key, lock_slice};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+#[test]
+fn overlapping_sets() {
+    let shards: Arc<Vec<Mutex<u32>>> = Arc::new((0..8).map(Mutex::new).collect());
+    let handles: Vec<_> = (0..4)
+        .map(|n| {
+            let shards = Arc::clone(&shards);
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    // Each thread names the shards in a different order.
+                    let wanted: Vec<&Mutex<u32>> = if n % 2 == 0 {
+                        shards.iter().collect()
+                    } else {
+                        shards.iter().rev().collect()
+                    };
+                    for guard in &mut lock_all(&wanted) {
+                        **guard += 1;
+                    }
+                    let indices: Vec<usize> = (0..shards.len()).rev().step_by(n + 1).collect();
+                    drop(lock_slice(&shards, &indices));
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    for (i, shard) in shards.iter().enumerate() {
+        assert_eq!(*shard.lock().unwrap(), i as u32 + 400);
+    }
+}
+
+#[test]
+fn duplicates() {
+    let shards = [Mutex::new(0), Mutex::new(1)];
+    assert_eq!(lock_all(&[&shards[1], &shards[1]]).len(), 1);
+    let guards = lock_slice(&shards, &[1, 0, 1, 0]);
+    let values: Vec<u32> = guards.iter().map(|g| **g).collect();
+    assert_eq!(values, [0, 1]);
+    assert!(lock_slice(&shards, &[]).is_empty());
+}
+
+#[test]
+fn by_key() {
+    let shards = [Mutex::new('a'), Mutex::new('b'), Mutex::new('c')];
+    // Taken last to first.
+    let key = |lock: &Mutex<char>| {
+        std::cmp::Reverse(shards.iter().position(|shard| std::ptr::eq(shard, lock)))
+    };
+    let guards = lock_all_by_key(&[&shards[0], &shards[2], &shards[0]], key);
+    let values: Vec<char> = guards.iter().map(|g| **g).collect();
+    assert_eq!(values, ['c', 'a']);
+}
+
+#[test]
+fn by_changing_key() {
+    let shards = [Mutex::new('a'), Mutex::new('b')];
+    // A key which differs each time, so copies of a lock could be keyed apart.
+    let mut calls = 0;
+    let key = |_: &Mutex<char>| {
+        calls += 1;
+        std::cmp::Reverse(calls)
+    };
+    let guards = lock_all_by_key(&[&shards[0], &shards[1], &shards[0]], key);
+    assert_eq!(guards.len(), 2);
+    drop(guards);
+    assert_eq!(calls, 2);
+}
+
+#[test]
+#[should_panic(expected = "lock index 3 is out of bounds for 2 locks")]
+fn out_of_bounds() {
+    let shards = [Mutex::new(0), Mutex::new(1)];
+    lock_slice(&shards, &[0, 3]);
+}
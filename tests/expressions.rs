@@ -0,0 +1,46 @@
+use lock_order::{lock, try_lock};
+use std::collections::HashMap;
+use std::sync::{Mutex, RwLock};
+
+struct Shards {
+    shards: Vec<Mutex<u32>>,
+    names: RwLock<HashMap<u32, String>>,
+}
+
+impl Shards {
+    fn shard(&self, i: usize) -> &Mutex<u32> {
+        &self.shards[i]
+    }
+
+    fn moves(&self, from: usize, to: usize) {
+        lock!(mut self.shard(from) as from, mut self.shards[to] as to, read self.names);
+        *to += *from;
+        *from = 0;
+        assert!(names.is_empty());
+    }
+}
+
+#[test]
+fn methods_and_indexing() {
+    let shards = Shards {
+        shards: vec![Mutex::new(1), Mutex::new(2)],
+        names: RwLock::new(HashMap::new()),
+    };
+    shards.moves(0, 1);
+    assert_eq!(*shards.shards[0].lock().unwrap(), 0);
+    assert_eq!(*shards.shards[1].lock().unwrap(), 3);
+    assert!(try_lock!(shards.shard(0), shards.shards[1]).is_some());
+}
+
+#[test]
+fn derefs_and_groups() {
+    let boxed = Box::new(Mutex::new(1));
+    let pair = (Mutex::new(2), Mutex::new(3));
+    {
+        lock!(mut *boxed, (pair.0) as first, mut pair.1 as second);
+        *boxed += *first;
+        *second += *first;
+    }
+    assert_eq!(*boxed.lock().unwrap(), 3);
+    assert_eq!(*pair.1.lock().unwrap(), 5);
+}
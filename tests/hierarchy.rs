@@ -0,0 +1,73 @@
+mod common;
+
+use common::Recorded;
+use lock_order::{lock, lock_hierarchy, try_lock};
+use std::cell::RefCell;
+
+lock_hierarchy!(registry < state < queue);
+
+#[test]
+fn ranked_order() {
+    let log = RefCell::new(Vec::new());
+    let registry = Recorded {
+        name: "registry",
+        log: &log,
+    };
+    let state = Recorded {
+        name: "state",
+        log: &log,
+    };
+    let queue = Recorded {
+        name: "queue",
+        log: &log,
+    };
+    {
+        lock!(order = hierarchy; queue, state, registry);
+        assert_eq!((registry, state, queue), ("registry", "state", "queue"));
+    }
+    assert_eq!(*log.borrow(), ["registry", "state", "queue"]);
+
+    log.borrow_mut().clear();
+    assert!(try_lock!(order = hierarchy; queue, registry).is_some());
+    assert_eq!(*log.borrow(), ["registry", "queue"]);
+}
+
+#[test]
+fn ranked_by_alias() {
+    let log = RefCell::new(Vec::new());
+    let first = Recorded {
+        name: "first",
+        log: &log,
+    };
+    let second = Recorded {
+        name: "second",
+        log: &log,
+    };
+    {
+        lock!(order = hierarchy; first as queue, second as registry);
+        assert_eq!((registry, queue), ("second", "first"));
+    }
+    assert_eq!(*log.borrow(), ["second", "first"]);
+}
+
+#[test]
+fn inline_rank() {
+    let log = RefCell::new(Vec::new());
+    let registry = Recorded {
+        name: "registry",
+        log: &log,
+    };
+    let cache = Recorded {
+        name: "cache",
+        log: &log,
+    };
+    let queue = Recorded {
+        name: "queue",
+        log: &log,
+    };
+    {
+        // `cache` isn't in the hierarchy, so is ranked between `registry` and `queue` inline.
+        lock!(order = hierarchy; queue, #[rank = 1] cache, registry);
+    }
+    assert_eq!(*log.borrow(), ["registry", "cache", "queue"]);
+}
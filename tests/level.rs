@@ -0,0 +1,81 @@
+use lock_order::{lock, lock_more, Level, LockLevel, OrderedMutex, OrderedRwLock};
+use std::cell::Cell;
+
+struct Bank {
+    accounts: OrderedMutex<u32, 10>,
+    ledger: OrderedRwLock<Vec<u32>, 20>,
+    audit: OrderedMutex<u32, 30>,
+}
+
+impl Bank {
+    fn new() -> Self {
+        Bank {
+            accounts: OrderedMutex::new(100),
+            ledger: OrderedRwLock::new(Vec::new()),
+            audit: OrderedMutex::new(0),
+        }
+    }
+
+    fn transfer(&self, level: &mut Level<'_, ()>, amount: u32) {
+        lock!(level = level; write mut self.ledger, mut self.accounts);
+        *accounts -= amount;
+        ledger.push(amount);
+        assert_eq!(level.level(), 20);
+        self.record(&mut level);
+    }
+
+    fn record(&self, level: &mut Level<'_, impl LockLevel>) {
+        *self.audit.lock(level).unwrap() += 1;
+    }
+}
+
+#[test]
+fn levels() {
+    let bank = Bank::new();
+    let mut level = Level::root();
+    assert_eq!(level.level(), 0);
+    bank.transfer(&mut level, 10);
+    bank.transfer(&mut level, 20);
+
+    assert_eq!(*bank.accounts.lock(&mut level).unwrap(), 70);
+    assert_eq!(*bank.ledger.read(&mut level).unwrap(), [10, 20]);
+    assert_eq!(*bank.audit.lock(&mut level).unwrap(), 2);
+}
+
+#[test]
+fn nested_tokens() {
+    let bank = Bank::new();
+    let mut level = Level::root();
+    {
+        lock!(level = level; mut bank.accounts);
+        *accounts += 1;
+        lock!(level = level; read bank.ledger, mut bank.audit);
+        *audit += ledger.len() as u32;
+        assert_eq!(level.level(), 30);
+    }
+    assert_eq!(*bank.accounts.lock(&mut level).unwrap(), 101);
+}
+
+#[test]
+fn more_levels() {
+    let bank = Bank::new();
+    let mut level = Level::root();
+    lock!(level = level; mut bank.accounts);
+    lock_more!(level = level; mut bank.audit);
+    *audit += *accounts;
+    assert_eq!(level.level(), 30);
+}
+
+#[test]
+fn level_evaluated_once() {
+    let bank = Bank::new();
+    let mut level = Level::root();
+    let calls = Cell::new(0);
+    let bank = || {
+        calls.set(calls.get() + 1);
+        &bank
+    };
+    lock!(level = level; bank().audit as audit, mut bank().accounts as accounts);
+    *accounts += *audit;
+    assert_eq!(calls.get(), 2);
+}
@@ -0,0 +1 @@
+//! Tests of ordering by a `lock_order.toml`, which needs a crate of its own to be found in.
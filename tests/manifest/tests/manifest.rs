@@ -0,0 +1,81 @@
+#[path = "../../common/mod.rs"]
+mod common;
+
+use common::Recorded;
+use lock_order::{lock, try_lock};
+use std::cell::RefCell;
+
+#[test]
+fn ranked_by_default() {
+    let log = RefCell::new(Vec::new());
+    let recorded = |name| Recorded { name, log: &log };
+    let (registry, state, queue) = (recorded("registry"), recorded("state"), recorded("queue"));
+    {
+        lock!(queue, state, registry);
+        assert_eq!((registry, state, queue), ("registry", "state", "queue"));
+    }
+    assert_eq!(*log.borrow(), ["registry", "state", "queue"]);
+
+    log.borrow_mut().clear();
+    assert!(try_lock!(queue, registry).is_some());
+    assert_eq!(*log.borrow(), ["registry", "queue"]);
+}
+
+#[test]
+fn ranked_by_path() {
+    let log = RefCell::new(Vec::new());
+    let recorded = |name| Recorded { name, log: &log };
+    let pair = (recorded("first"), recorded("second"));
+    let registry = recorded("registry");
+    {
+        lock!(registry, pair.1 as second);
+        assert_eq!((registry, second), ("registry", "second"));
+    }
+    assert_eq!(*log.borrow(), ["second", "registry"]);
+}
+
+#[test]
+fn ranked_by_section() {
+    let log = RefCell::new(Vec::new());
+    let recorded = |name| Recorded { name, log: &log };
+    let (sessions, state, registry) = (
+        recorded("sessions"),
+        recorded("state"),
+        recorded("registry"),
+    );
+    {
+        lock!(order = manifest(net::sessions); state, sessions, registry);
+        assert_eq!((sessions, state), ("sessions", "state"));
+    }
+    assert_eq!(*log.borrow(), ["registry", "sessions", "state"]);
+}
+
+#[test]
+fn explicit_order() {
+    let log = RefCell::new(Vec::new());
+    let recorded = |name| Recorded { name, log: &log };
+    let (state, queue) = (recorded("state"), recorded("queue"));
+    {
+        lock!(order = binding; state, queue);
+        assert_eq!((state, queue), ("state", "queue"));
+    }
+    assert_eq!(*log.borrow(), ["queue", "state"]);
+}
+
+#[test]
+fn ranked_inline() {
+    let log = RefCell::new(Vec::new());
+    let recorded = |name| Recorded { name, log: &log };
+    let (registry, state, cache) = (recorded("registry"), recorded("state"), recorded("cache"));
+    {
+        // `cache` has no rank in the manifest, and `registry` is moved after `state`.
+        lock!(
+            #[rank = 25]
+            registry,
+            state,
+            #[rank = 15]
+            cache
+        );
+    }
+    assert_eq!(*log.borrow(), ["cache", "state", "registry"]);
+}
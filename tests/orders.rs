@@ -0,0 +1,140 @@
+mod common;
+
+use common::Recorded;
+use lock_order::{lock, try_lock};
+use std::cell::{Cell, RefCell};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+struct Shard<'a> {
+    state: Recorded<'a>,
+}
+
+#[test]
+fn ties() {
+    let log = RefCell::new(Vec::new());
+    let a = Shard {
+        state: Recorded {
+            name: "a",
+            log: &log,
+        },
+    };
+    let b = Shard {
+        state: Recorded {
+            name: "b",
+            log: &log,
+        },
+    };
+    {
+        lock!(order = field; b.state as b_state, a.state as a_state);
+        assert_eq!(a_state, "a");
+    }
+    assert_eq!(*log.borrow(), ["a", "b"]);
+}
+
+#[test]
+fn path() {
+    let log = RefCell::new(Vec::new());
+    let a = Shard {
+        state: Recorded {
+            name: "a",
+            log: &log,
+        },
+    };
+    let b = Shard {
+        state: Recorded {
+            name: "b",
+            log: &log,
+        },
+    };
+    {
+        // By name `first` would be taken before `second`.
+        lock!(order = path; a.state as second, b.state as first);
+        assert_eq!((first, second), ("b", "a"));
+    }
+    assert_eq!(*log.borrow(), ["a", "b"]);
+}
+
+#[test]
+fn natural() {
+    let log = RefCell::new(Vec::new());
+    let lock2 = Recorded {
+        name: "2",
+        log: &log,
+    };
+    let lock10 = Recorded {
+        name: "10",
+        log: &log,
+    };
+    let lock1 = Recorded {
+        name: "1",
+        log: &log,
+    };
+    {
+        lock!(order = natural; lock10, lock2, lock1);
+    }
+    assert_eq!(*log.borrow(), ["1", "2", "10"]);
+    log.borrow_mut().clear();
+    assert!(try_lock!(order = natural; lock10, lock2).is_some());
+    assert_eq!(*log.borrow(), ["2", "10"]);
+}
+
+#[test]
+fn address() {
+    let pair = Arc::new([Mutex::new(0), Mutex::new(0)]);
+    let handles: Vec<_> = (0..4)
+        .map(|n| {
+            let pair = Arc::clone(&pair);
+            thread::spawn(move || {
+                for _ in 0..1000 {
+                    // By name these would be taken in opposite orders, and could deadlock.
+                    if n % 2 == 0 {
+                        lock!(order = address; mut pair[0] as first, mut pair[1] as second);
+                        *first += 1;
+                        *second += 1;
+                    } else {
+                        lock!(order = address; mut pair[1] as first, mut pair[0] as second);
+                        *first += 1;
+                        *second += 1;
+                    }
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    let shared = &*pair;
+    let guards = try_lock!(order = address; shared[1] as last, shared[0] as first);
+    let (last, first) = guards.expect("the locks are free");
+    assert_eq!(*first + *last, 8000);
+}
+
+#[test]
+fn address_evaluated_once() {
+    let pair = [Mutex::new(1), Mutex::new(2)];
+    let calls = Cell::new(0);
+    let shard = |i: usize| {
+        calls.set(calls.get() + 1);
+        &pair[i]
+    };
+    {
+        lock!(order = address; shard(1) as second, shard(0) as first);
+        assert_eq!(*second - *first, 1);
+    }
+    assert_eq!(calls.get(), 2);
+    assert!(try_lock!(order = address; shard(1), shard(0)).is_some());
+    assert_eq!(calls.get(), 4);
+}
+
+#[test]
+fn most_taken() {
+    let l: Vec<_> = (0..12).map(Mutex::new).collect();
+    lock!(
+        order = address;
+        l[11] as a, l[10] as b, l[9] as c, l[8] as d, l[7] as e, l[6] as f,
+        l[5] as g, l[4] as h, l[3] as i, l[2] as j, l[1] as k, l[0] as m
+    );
+    let total = *a + *b + *c + *d + *e + *f + *g + *h + *i + *j + *k + *m;
+    assert_eq!(total, 66);
+}
@@ -0,0 +1,87 @@
+use lock_order::{lock, unlock, TimeoutError};
+use std::time::Duration;
+
+#[test]
+fn parking_lot_usage() {
+    let config = parking_lot::RwLock::new(1);
+    let queue = parking_lot::Mutex::new(2);
+    {
+        lock!(parking_lot: read config, mut queue);
+        *queue += *config;
+    }
+    {
+        lock!(parking_lot: write mut config);
+        *config = 5;
+    }
+    assert_eq!(*queue.lock(), 3);
+    assert_eq!(*config.read(), 5);
+}
+
+#[test]
+fn explicit_std() {
+    let queue = std::sync::Mutex::new(2);
+    {
+        lock!(std: mut queue);
+        *queue = 3;
+    }
+    assert_eq!(*queue.lock().unwrap(), 3);
+}
+
+fn add_within(
+    config: &parking_lot::RwLock<u32>,
+    queue: &parking_lot::Mutex<u32>,
+) -> Result<(), TimeoutError> {
+    lock!(parking_lot: timeout = Duration::from_millis(10); read config, mut queue);
+    *queue += *config;
+    Ok(())
+}
+
+#[test]
+fn timeout() {
+    let config = parking_lot::RwLock::new(1);
+    let queue = parking_lot::Mutex::new(2);
+    assert_eq!(add_within(&config, &queue), Ok(()));
+    {
+        let _held = queue.lock();
+        let err = add_within(&config, &queue).unwrap_err();
+        assert_eq!(err.lock(), "queue");
+        // `config` was taken before `queue` timed out, and has been released again.
+        assert!(config.try_write_until(std::time::Instant::now()).is_some());
+    }
+    assert_eq!(*queue.lock(), 3);
+}
+
+#[test]
+fn early_release() {
+    let config = parking_lot::RwLock::new(1);
+    let queue = parking_lot::Mutex::new(2);
+    lock!(parking_lot: read config as factor, mut queue as total);
+    *total += *factor;
+    unlock!(total);
+    assert_eq!(queue.try_lock().map(|queue| *queue), Some(3));
+    assert_eq!(*factor, 1);
+}
+
+#[cfg(feature = "checked")]
+struct Queue {
+    jobs: parking_lot::Mutex<u32>,
+}
+
+#[cfg(feature = "checked")]
+fn drain(jobs: &parking_lot::Mutex<u32>) {
+    lock!(parking_lot: mut jobs);
+    *jobs = 0;
+}
+
+#[cfg(feature = "checked")]
+#[test]
+#[should_panic(expected = "lock re-entry: `jobs` taken at")]
+fn reentry_through_reference() {
+    let queue = Queue {
+        jobs: parking_lot::Mutex::new(1),
+    };
+    lock!(parking_lot: queue.jobs);
+    assert_eq!(*jobs, 1);
+    // The same lock, reached through a reference to it.
+    drain(&queue.jobs);
+}
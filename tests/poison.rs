@@ -0,0 +1,53 @@
+use lock_order::{lock, try_lock, PoisonError};
+use std::sync::{Mutex, RwLock};
+use std::thread;
+
+fn poison<T: Send>(lock: &Mutex<T>) {
+    thread::scope(|s| {
+        s.spawn(|| {
+            let _guard = lock.lock().unwrap();
+            panic!("poisoning the lock");
+        })
+        .join()
+        .unwrap_err();
+    });
+}
+
+#[test]
+fn recover() {
+    let queue = Mutex::new(1);
+    let config = RwLock::new(2);
+    poison(&queue);
+    {
+        lock!(poison = recover; mut queue, read config);
+        *queue += *config;
+    }
+    let attempt = try_lock!(poison = recover; queue);
+    assert_eq!(attempt.map(|queue| *queue), Some(3));
+}
+
+fn add(queue: &Mutex<u32>, config: &Mutex<u32>) -> Result<(), PoisonError> {
+    lock!(poison = propagate; mut queue, config);
+    *queue += *config;
+    Ok(())
+}
+
+#[test]
+fn propagate() {
+    let queue = Mutex::new(1);
+    let config = Mutex::new(2);
+    assert_eq!(add(&queue, &config), Ok(()));
+    poison(&queue);
+    let err = add(&queue, &config).unwrap_err();
+    assert_eq!(err.lock(), "queue");
+    // `config` was taken before `queue` was found to be poisoned, and has been released again.
+    assert!(config.try_lock().is_ok());
+}
+
+#[test]
+#[should_panic]
+fn panic() {
+    let queue = Mutex::new(1);
+    poison(&queue);
+    lock!(poison = panic; queue);
+}
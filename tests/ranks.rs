@@ -0,0 +1,121 @@
+mod common;
+
+use common::Recorded;
+use lock_order::{lock, try_lock, LockRanks};
+use std::cell::RefCell;
+use std::sync::Mutex;
+
+#[derive(LockRanks)]
+struct Locks<'a> {
+    #[lock_rank(30)]
+    connections: Recorded<'a>,
+    #[lock_rank(10)]
+    sessions: Recorded<'a>,
+    #[lock_rank(20)]
+    accounts: Recorded<'a>,
+    #[lock_rank(20)]
+    audit: Recorded<'a>,
+}
+
+struct Server<'a> {
+    locks: Locks<'a>,
+}
+
+impl<'a> Server<'a> {
+    fn new(log: &'a RefCell<Vec<&'static str>>) -> Self {
+        let recorded = |name| Recorded { name, log };
+        Server {
+            locks: Locks {
+                connections: recorded("connections"),
+                sessions: recorded("sessions"),
+                accounts: recorded("accounts"),
+                audit: recorded("audit"),
+            },
+        }
+    }
+
+    fn connect(&self) {
+        lock!(order = rank; self.locks.connections, self.locks.sessions, self.locks.audit);
+        assert_eq!(
+            (&*connections, &*sessions, &*audit),
+            ("connections", "sessions", "audit")
+        );
+    }
+}
+
+#[test]
+fn ranked_fields() {
+    let log = RefCell::new(Vec::new());
+    let server = Server::new(&log);
+    server.connect();
+    assert_eq!(*log.borrow(), ["sessions", "audit", "connections"]);
+
+    log.borrow_mut().clear();
+    let guards = try_lock!(
+        order = rank;
+        server.locks.audit,
+        server.locks.connections,
+        server.locks.accounts
+    );
+    assert_eq!(guards, Some(("audit", "connections", "accounts")));
+    // Equal ranks are taken in the order of their names.
+    assert_eq!(*log.borrow(), ["accounts", "audit", "connections"]);
+}
+
+#[derive(LockRanks)]
+struct Pair<T>(#[lock_rank(2)] Mutex<T>, #[lock_rank(1)] Mutex<T>)
+where
+    T: Copy;
+
+#[test]
+fn tuple_fields() {
+    let pair = Pair(Mutex::new(1), Mutex::new(2));
+    {
+        lock!(order = rank; mut pair.0 as first, pair.1 as second);
+        *first += *second;
+    }
+    assert_eq!(*pair.0.lock().unwrap(), 3);
+}
+
+#[test]
+fn inline_ranks() {
+    let log = RefCell::new(Vec::new());
+    let server = Server::new(&log);
+    {
+        let locks = &server.locks;
+        lock!(
+            #[rank = 40]
+            locks.sessions,
+            #[rank = 1]
+            locks.accounts
+        );
+        assert_eq!((sessions, accounts), ("sessions", "accounts"));
+    }
+    assert_eq!(*log.borrow(), ["accounts", "sessions"]);
+
+    // An inline rank takes the place of a field's own rank.
+    log.borrow_mut().clear();
+    {
+        lock!(order = rank; #[rank = 40] server.locks.sessions, server.locks.accounts);
+    }
+    assert_eq!(*log.borrow(), ["accounts", "sessions"]);
+}
+
+#[derive(LockRanks)]
+struct Keywords {
+    #[lock_rank(2)]
+    r#type: Mutex<u32>,
+    #[lock_rank(1)]
+    r#ref: Mutex<u32>,
+}
+
+#[test]
+fn raw_identifiers() {
+    let keywords = Keywords {
+        r#type: Mutex::new(1),
+        r#ref: Mutex::new(2),
+    };
+    lock!(order = rank; mut keywords.r#type, keywords.r#ref);
+    *r#type += *r#ref;
+    assert_eq!(*r#type, 3);
+}
@@ -0,0 +1,150 @@
+mod common;
+
+use common::Released;
+use lock_order::{lock, lock_hierarchy, unlock, LockRanks, PoisonError};
+use std::cell::RefCell;
+
+lock_hierarchy!(registry < state < queue);
+
+#[test]
+fn reverse_order() {
+    let log = RefCell::new(Vec::new());
+    let released = |name| Released {
+        name,
+        log: &log,
+        poisoned: false,
+    };
+    let (a, b, c) = (released("a"), released("b"), released("c"));
+    {
+        lock!(c, a, b);
+    }
+    assert_eq!(
+        *log.borrow(),
+        [
+            "take a",
+            "take b",
+            "take c",
+            "release c",
+            "release b",
+            "release a"
+        ]
+    );
+
+    log.borrow_mut().clear();
+    let (registry, state, queue) = (released("registry"), released("state"), released("queue"));
+    {
+        lock!(order = hierarchy; queue, registry, state);
+    }
+    assert_eq!(
+        *log.borrow(),
+        [
+            "take registry",
+            "take state",
+            "take queue",
+            "release queue",
+            "release state",
+            "release registry"
+        ]
+    );
+}
+
+#[derive(LockRanks)]
+struct Ranked<'a> {
+    #[lock_rank(3)]
+    a: Released<'a>,
+    #[lock_rank(1)]
+    b: Released<'a>,
+    #[lock_rank(2)]
+    c: Released<'a>,
+}
+
+#[test]
+fn reverse_order_at_runtime() {
+    let log = RefCell::new(Vec::new());
+    let released = |name| Released {
+        name,
+        log: &log,
+        poisoned: false,
+    };
+    let locks = Ranked {
+        a: released("a"),
+        b: released("b"),
+        c: released("c"),
+    };
+    {
+        lock!(order = rank; locks.a, locks.b, locks.c);
+    }
+    assert_eq!(
+        *log.borrow(),
+        [
+            "take b",
+            "take c",
+            "take a",
+            "release a",
+            "release c",
+            "release b"
+        ]
+    );
+
+    // A lock released early goes straight away, and the rest still go in reverse.
+    log.borrow_mut().clear();
+    {
+        lock!(order = rank; locks.a, locks.b, locks.c);
+        unlock!(c);
+        log.borrow_mut().push("unlocked c".to_string());
+    }
+    assert_eq!(
+        *log.borrow(),
+        [
+            "take b",
+            "take c",
+            "take a",
+            "release c",
+            "unlocked c",
+            "release a",
+            "release b"
+        ]
+    );
+}
+
+fn take_all(a: &Released, b: &Released, c: &Released) -> Result<(), PoisonError> {
+    lock!(poison = propagate; a, b, c);
+    Ok(())
+}
+
+fn take_ranked(locks: &Ranked) -> Result<(), PoisonError> {
+    lock!(order = rank; poison = propagate; locks.a, locks.b, locks.c);
+    Ok(())
+}
+
+#[test]
+fn reverse_order_on_error() {
+    let log = RefCell::new(Vec::new());
+    let released = |name, poisoned| Released {
+        name,
+        log: &log,
+        poisoned,
+    };
+    let (a, b, c) = (
+        released("a", false),
+        released("b", false),
+        released("c", true),
+    );
+    assert_eq!(take_all(&a, &b, &c).map_err(|e| e.lock()), Err("c"));
+    assert_eq!(
+        *log.borrow(),
+        ["take a", "take b", "release b", "release a"]
+    );
+
+    log.borrow_mut().clear();
+    let locks = Ranked {
+        a: released("a", true),
+        b: released("b", false),
+        c: released("c", false),
+    };
+    assert_eq!(take_ranked(&locks).map_err(|e| e.lock()), Err("locks.a"));
+    assert_eq!(
+        *log.borrow(),
+        ["take b", "take c", "release c", "release b"]
+    );
+}
@@ -0,0 +1,62 @@
+//! Stand-in for the `parking_lot` API the `lock_order` tests use, whose locks hand back the guard
+//! without a `Result`. It's built on the std locks, polling them for the timed methods.
+
+use std::sync::{MutexGuard, RwLockReadGuard, RwLockWriteGuard};
+use std::time::Instant;
+
+/// Poll `attempt` until it succeeds or `deadline` passes.
+fn until<G>(deadline: Instant, mut attempt: impl FnMut() -> Option<G>) -> Option<G> {
+    loop {
+        if let Some(guard) = attempt() {
+            return Some(guard);
+        }
+        if Instant::now() >= deadline {
+            return None;
+        }
+        std::thread::yield_now();
+    }
+}
+
+pub struct Mutex<T>(std::sync::Mutex<T>);
+
+impl<T> Mutex<T> {
+    pub fn new(t: T) -> Self {
+        Mutex(std::sync::Mutex::new(t))
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        self.0.lock().unwrap()
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.0.try_lock().ok()
+    }
+
+    pub fn try_lock_until(&self, deadline: Instant) -> Option<MutexGuard<'_, T>> {
+        until(deadline, || self.0.try_lock().ok())
+    }
+}
+
+pub struct RwLock<T>(std::sync::RwLock<T>);
+
+impl<T> RwLock<T> {
+    pub fn new(t: T) -> Self {
+        RwLock(std::sync::RwLock::new(t))
+    }
+
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        self.0.read().unwrap()
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        self.0.write().unwrap()
+    }
+
+    pub fn try_read_until(&self, deadline: Instant) -> Option<RwLockReadGuard<'_, T>> {
+        until(deadline, || self.0.try_read().ok())
+    }
+
+    pub fn try_write_until(&self, deadline: Instant) -> Option<RwLockWriteGuard<'_, T>> {
+        until(deadline, || self.0.try_write().ok())
+    }
+}
@@ -0,0 +1,141 @@
+//! Stand-in for the `tokio` API the `lock_order` tests use: locks acquired through a future,
+//! `time::timeout_at` and a `Runtime` to block on. It's built on the std locks, with futures
+//! which stay pending while their lock is held elsewhere and a runtime which polls them until
+//! they're ready.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// A future polling `attempt` until it succeeds, waking itself to be polled again each time it
+/// doesn't.
+struct Attempt<F>(F);
+
+impl<G, F: FnMut() -> Option<G> + Unpin> Future for Attempt<F> {
+    type Output = G;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<G> {
+        match (self.0)() {
+            Some(guard) => Poll::Ready(guard),
+            None => {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+}
+
+pub mod sync {
+    use super::Attempt;
+    use std::future::Future;
+    use std::sync::{MutexGuard, RwLockReadGuard, RwLockWriteGuard};
+
+    #[derive(Debug)]
+    pub struct TryLockError(());
+
+    pub struct Mutex<T>(std::sync::Mutex<T>);
+
+    impl<T> Mutex<T> {
+        pub fn new(t: T) -> Self {
+            Mutex(std::sync::Mutex::new(t))
+        }
+
+        pub fn lock(&self) -> impl Future<Output = MutexGuard<'_, T>> {
+            Attempt(move || self.0.try_lock().ok())
+        }
+
+        pub fn try_lock(&self) -> Result<MutexGuard<'_, T>, TryLockError> {
+            self.0.try_lock().map_err(|_| TryLockError(()))
+        }
+    }
+
+    pub struct RwLock<T>(std::sync::RwLock<T>);
+
+    impl<T> RwLock<T> {
+        pub fn new(t: T) -> Self {
+            RwLock(std::sync::RwLock::new(t))
+        }
+
+        pub fn read(&self) -> impl Future<Output = RwLockReadGuard<'_, T>> {
+            Attempt(move || self.0.try_read().ok())
+        }
+
+        pub fn write(&self) -> impl Future<Output = RwLockWriteGuard<'_, T>> {
+            Attempt(move || self.0.try_write().ok())
+        }
+
+        pub fn try_write(&self) -> Result<RwLockWriteGuard<'_, T>, TryLockError> {
+            self.0.try_write().map_err(|_| TryLockError(()))
+        }
+    }
+}
+
+pub mod time {
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    pub mod error {
+        #[derive(Debug)]
+        pub struct Elapsed(pub(crate) ());
+    }
+
+    #[derive(Clone, Copy, Debug)]
+    pub struct Instant(std::time::Instant);
+
+    impl Instant {
+        pub fn from_std(instant: std::time::Instant) -> Self {
+            Instant(instant)
+        }
+    }
+
+    pub struct Timeout<F> {
+        deadline: std::time::Instant,
+        future: Pin<Box<F>>,
+    }
+
+    impl<F: Future> Future for Timeout<F> {
+        type Output = Result<F::Output, error::Elapsed>;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+            match self.future.as_mut().poll(cx) {
+                Poll::Ready(out) => Poll::Ready(Ok(out)),
+                Poll::Pending if std::time::Instant::now() >= self.deadline => {
+                    Poll::Ready(Err(error::Elapsed(())))
+                }
+                Poll::Pending => Poll::Pending,
+            }
+        }
+    }
+
+    pub fn timeout_at<F: Future>(deadline: Instant, future: F) -> Timeout<F> {
+        Timeout {
+            deadline: deadline.0,
+            future: Box::pin(future),
+        }
+    }
+}
+
+pub mod runtime {
+    use std::future::Future;
+    use std::task::{Context, Poll, Waker};
+
+    pub struct Runtime(());
+
+    impl Runtime {
+        pub fn new() -> std::io::Result<Self> {
+            Ok(Runtime(()))
+        }
+
+        pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+            let mut future = Box::pin(future);
+            let mut cx = Context::from_waker(Waker::noop());
+            loop {
+                if let Poll::Ready(out) = future.as_mut().poll(&mut cx) {
+                    return out;
+                }
+                std::thread::yield_now();
+            }
+        }
+    }
+}
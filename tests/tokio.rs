@@ -0,0 +1,69 @@
+// The stand-in tokio locks hand back std guards.
+#![allow(clippy::await_holding_lock)]
+
+use lock_order::{async_lock, lock, unlock, TimeoutError};
+use std::future::Future;
+use std::time::Duration;
+use tokio::sync::{Mutex, RwLock};
+
+fn block_on<F: Future>(f: F) -> F::Output {
+    tokio::runtime::Runtime::new().unwrap().block_on(f)
+}
+
+#[test]
+fn async_usage() {
+    let config = RwLock::new(1);
+    let queue = Mutex::new(2);
+    block_on(async {
+        {
+            async_lock!(read config, mut queue);
+            *queue += *config;
+        }
+        {
+            lock!(tokio: write mut config);
+            *config = 5;
+        }
+    });
+    block_on(async {
+        async_lock!(read config, queue);
+        assert_eq!(*queue, 3);
+        assert_eq!(*config, 5);
+    });
+}
+
+async fn add_within(config: &RwLock<u32>, queue: &Mutex<u32>) -> Result<(), TimeoutError> {
+    async_lock!(timeout = Duration::from_millis(10); read config, mut queue);
+    *queue += *config;
+    Ok(())
+}
+
+#[test]
+fn timeout() {
+    let config = RwLock::new(1);
+    let queue = Mutex::new(2);
+    block_on(async {
+        assert_eq!(add_within(&config, &queue).await, Ok(()));
+        {
+            let _held = queue.lock().await;
+            let err = add_within(&config, &queue).await.unwrap_err();
+            assert_eq!(err.lock(), "queue");
+            // `config` was taken before `queue` timed out, and has been released again.
+            assert!(config.try_write().is_ok());
+        }
+        async_lock!(queue);
+        assert_eq!(*queue, 3);
+    });
+}
+
+#[test]
+fn early_release() {
+    let config = RwLock::new(1);
+    let queue = Mutex::new(2);
+    block_on(async {
+        async_lock!(read config as factor, mut queue as total);
+        *total += *factor;
+        unlock!(total);
+        assert_eq!(queue.try_lock().map(|queue| *queue).ok(), Some(3));
+        assert_eq!(*factor, 1);
+    });
+}
@@ -0,0 +1,27 @@
+use lock_order::try_lock;
+use std::sync::{Mutex, RwLock};
+
+#[test]
+fn all_free() {
+    let queue = Mutex::new(1);
+    let config = RwLock::new(2);
+    match try_lock!(mut queue, read config) {
+        Some((mut queue, config)) => *queue += *config,
+        None => panic!("nothing else holds the locks"),
+    }
+    assert_eq!(*queue.lock().unwrap(), 3);
+}
+
+#[test]
+fn one_held() {
+    let queue = Mutex::new(1);
+    let config = RwLock::new(2);
+    let state = Mutex::new(3);
+    {
+        let _held = state.lock().unwrap();
+        assert!(try_lock!(queue, read config, state).is_none());
+        // The locks taken before `state` was found to be held have been released again.
+        assert!(try_lock!(queue, write config).is_some());
+    }
+    assert!(try_lock!(queue, read config, state).is_some());
+}
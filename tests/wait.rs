@@ -0,0 +1,85 @@
+use lock_order::{lock, wait_locked, TimeoutError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+struct Shared {
+    queue: Mutex<Vec<u32>>,
+    config: Mutex<u32>,
+    ready: Condvar,
+}
+
+impl Shared {
+    fn new() -> Arc<Self> {
+        Arc::new(Shared {
+            queue: Mutex::new(Vec::new()),
+            config: Mutex::new(2),
+            ready: Condvar::new(),
+        })
+    }
+
+    fn push(&self, item: u32) {
+        lock!(mut self.queue);
+        queue.push(item);
+        self.ready.notify_all();
+    }
+}
+
+#[test]
+fn wait() {
+    let shared = Shared::new();
+    lock!(mut shared.queue, shared.config);
+    let producer = Arc::clone(&shared);
+    let handle = thread::spawn(move || {
+        // Only gets `config` while `main` waits, as it's released first.
+        lock!(mut producer.config);
+        *config += 1;
+        drop(config);
+        producer.push(1);
+    });
+    // Without a condition this can wake spuriously, but still holds both locks after.
+    wait_locked!(shared.ready, mut shared.queue; shared.config);
+    assert!(queue.len() <= 1);
+    assert!(*config <= 3);
+    assert!(shared.config.try_lock().is_err());
+    drop((queue, config));
+    handle.join().unwrap();
+}
+
+#[test]
+fn condition() {
+    let shared = Shared::new();
+    let producer = Arc::clone(&shared);
+    let handle = thread::spawn(move || {
+        for item in 0..3 {
+            producer.push(item);
+        }
+    });
+    lock!(shared.config, mut shared.queue);
+    wait_locked!(shared.ready, mut shared.queue; shared.config; while queue.len() < 3);
+    assert_eq!(*queue, [0, 1, 2]);
+    assert_eq!(*config, 2);
+    drop((queue, config));
+    handle.join().unwrap();
+}
+
+fn wait_briefly(shared: &Shared) -> Result<usize, TimeoutError> {
+    lock!(mut shared.queue);
+    wait_locked!(
+        timeout = Duration::from_millis(10);
+        shared.ready, mut shared.queue; while queue.is_empty()
+    );
+    Ok(queue.len())
+}
+
+#[test]
+fn timeout() {
+    let shared = Shared::new();
+    let error = wait_briefly(&shared).unwrap_err();
+    assert_eq!(error.lock(), "shared.queue");
+    // The lock isn't held once the wait times out.
+    assert!(shared.queue.try_lock().is_ok());
+
+    shared.push(1);
+    assert_eq!(wait_briefly(&shared), Ok(1));
+}
@@ -0,0 +1,28 @@
+use lock_order::{with_locks, PoisonError};
+use std::sync::{Mutex, RwLock};
+
+#[test]
+fn scoped() {
+    let queue = Mutex::new(1);
+    let config = RwLock::new(2);
+    let total = with_locks!(mut queue, read config => {
+        *queue += *config;
+        *queue
+    });
+    assert_eq!(total, 3);
+    assert_eq!(*queue.lock().unwrap(), 3);
+
+    with_locks!(mut queue => *queue = 0);
+    assert_eq!(with_locks!(queue, read config => *queue + *config), 2);
+}
+
+fn total(queue: &Mutex<u32>, config: &Mutex<u32>) -> Result<u32, PoisonError> {
+    Ok(with_locks!(poison = propagate; queue, config => *queue + *config))
+}
+
+#[test]
+fn options() {
+    let queue = Mutex::new(1);
+    let config = Mutex::new(2);
+    assert_eq!(total(&queue, &config), Ok(3));
+}